@@ -0,0 +1,147 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use pingmole::{Coord, DistanceModel, Protocol, Relay, RelaysLoader, RelaysLoaderConfig};
+use serde_json::{json, Value};
+
+/// Number of countries, cities per country and relays per city. Mullvad lists under a thousand
+/// relays today, so this is a few times that to see how parsing scales.
+const COUNTRIES: usize = 50;
+const CITIES: usize = 4;
+const RELAYS: usize = 25;
+
+/// Builds a relay file shaped like the one cached by the Mullvad app, including the fields we don't
+/// read, so skipping them is part of the measurement.
+fn relay_file() -> String {
+  let countries = (0..COUNTRIES)
+    .map(|country| {
+      let cities = (0..CITIES)
+        .map(|city| {
+          let relays = (0..RELAYS)
+            .map(|relay| {
+              let endpoint_data = match relay % 3 {
+                | 0 => json!("openvpn"),
+                | 1 => {
+                  json!({
+                    "wireguard": {
+                      "public_key": "dGhpcyBpcyBub3QgYSByZWFsIGtleQ==",
+                      "daita": relay % 2 == 0,
+                    },
+                  })
+                },
+                | _ => json!("bridge"),
+              };
+
+              json!({
+                "hostname": format!("c{country}-c{city}-wg-{relay:03}"),
+                "ipv4_addr_in": format!("10.{country}.{city}.{relay}"),
+                "ipv6_addr_in": format!("2a03:1b20:{country}:{city}::a{relay:02x}"),
+                "include_in_country": true,
+                "active": relay % 10 != 0,
+                "owned": relay % 2 == 0,
+                "provider": "31173",
+                "weight": 100,
+                "endpoint_data": endpoint_data,
+                "location": {
+                  "country": format!("Country {country}"),
+                  "city": format!("City {city}"),
+                  "latitude": 50.0 + city as f64,
+                  "longitude": 10.0 + country as f64,
+                },
+              })
+            })
+            .collect::<Vec<_>>();
+
+          json!({
+            "name": format!("City {city}"),
+            "code": format!("c{city}"),
+            "latitude": 50.0 + city as f64,
+            "longitude": 10.0 + country as f64,
+            "relays": relays,
+          })
+        })
+        .collect::<Vec<_>>();
+
+      json!({
+        "name": format!("Country {country}"),
+        "code": format!("c{country}"),
+        "cities": cities,
+      })
+    })
+    .collect::<Vec<_>>();
+
+  json!({ "countries": countries, "etag": "bench" }).to_string()
+}
+
+fn config() -> RelaysLoaderConfig {
+  RelaysLoaderConfig {
+    location: Coord::new(59.3289, 18.0649),
+    include_inactive: false,
+    distance_model: DistanceModel::GreatCircle,
+    relays_file: None,
+    remote_fallback: false,
+    relays_url: None,
+  }
+}
+
+/// Mirrors how relays used to be loaded: parsing the file into a `Value` tree and then indexing
+/// into it field by field.
+fn walk_value_tree(data: &str, config: &RelaysLoaderConfig) -> Vec<Relay> {
+  let data = serde_json::from_str::<Value>(data).unwrap();
+  let mut results = Vec::new();
+
+  for country in data["countries"].as_array().unwrap() {
+    for city in country["cities"].as_array().unwrap() {
+      for relay in city["relays"].as_array().unwrap() {
+        let protocol = match &relay["endpoint_data"] {
+          | Value::String(kind) if kind == "openvpn" => Protocol::OpenVPN,
+          | Value::Object(data) if data.contains_key("wireguard") => Protocol::WireGuard,
+          | _ => continue,
+        };
+
+        let coord = Coord::new(
+          city["latitude"].as_f64().unwrap(),
+          city["longitude"].as_f64().unwrap(),
+        );
+
+        let relay = Relay {
+          coord: Some(coord),
+          protocol: Some(protocol),
+          distance: Some(config.location.distance_to(&coord)),
+          hostname: relay["hostname"].as_str().map(String::from),
+          ipv6: relay["ipv6_addr_in"].as_str().map(String::from),
+          city: city["name"].as_str().unwrap().to_string(),
+          country: country["name"].as_str().unwrap().to_string(),
+          provider: relay["provider"].as_str().map(String::from),
+          is_active: relay["active"].as_bool().unwrap(),
+          is_mullvad_owned: relay["owned"].as_bool().unwrap(),
+          ..Relay::from_target(relay["ipv4_addr_in"].as_str().unwrap())
+        };
+
+        if relay.is_active || config.include_inactive {
+          results.push(relay);
+        }
+      }
+    }
+  }
+
+  results
+}
+
+fn parse_relays(c: &mut Criterion) {
+  let data = relay_file();
+  let config = config();
+
+  let mut group = c.benchmark_group("parse_relays");
+
+  group.bench_function("value_tree", |b| {
+    b.iter(|| walk_value_tree(black_box(&data), &config))
+  });
+
+  group.bench_function("typed", |b| {
+    b.iter(|| RelaysLoader::parse_relays(black_box(&data), &config).unwrap())
+  });
+
+  group.finish();
+}
+
+criterion_group!(benches, parse_relays);
+criterion_main!(benches);
@@ -1,89 +1,967 @@
-use std::sync::Arc;
-use std::time::Duration;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::process;
+use std::time::{Duration, Instant};
 
 use clap::Parser;
-use tokio::time;
+use tokio::{signal, time};
 
-use crate::cli::{Cli, Spinner};
-use crate::coord::Coord;
-use crate::filters::{FilterByDistance, FilterByProtocol, FilterByRTT};
-use crate::pinger::{RelayPingerConfig, RelaysPinger};
-use crate::relays::{RelaysLoader, RelaysLoaderConfig};
-use crate::reporter::Reporter;
+use crate::cli::{Cli, Command, ProtocolSelector, Spinner};
+use crate::coord::{Coord, CoordError};
+use crate::filters::{
+  Filter, FilterByDistance, FilterByExcludedIp, FilterByFeature, FilterByJitter,
+  FilterByMultiProtocol, FilterByOwnership, FilterByProtocol, FilterByRTT, FilterByResponsive,
+  FilterByRole,
+};
+use crate::pinger::{
+  PingReport, PingResults, RelayPingerConfig, RelayPingerConfigs, RelayTimed, RelaysPinger,
+  PING_PORT,
+};
+use crate::relays::{
+  self, Protocol, Relay, RelayRole, RelaysLoader, RelaysLoaderConfig, RelaysSource,
+};
+use crate::reporter::{OutputFormat, Reporter, ReporterConfig, ReporterError, SortBy};
+
+/// Default SOCKS5 port of a local Tor daemon.
+const TOR_SOCKS_ADDR: &str = "127.0.0.1:9050";
+
+/// Radius (in km) beyond which the search isn't expanded anymore. Roughly half of the Earth's
+/// circumference, so every relay is within it.
+const FALLBACK_RADIUS_CAP_KM: f64 = 20_000.0;
+
+/// Number of relays above which the user is asked to confirm pinging them in interactive sessions.
+const RELAYS_SOFT_CAP: usize = 100;
+
+/// Identifies a relay across rounds. Relays of different protocols on the same host share an IP.
+type RelayKey = (String, Option<Protocol>, RelayRole);
 
 pub async fn run() -> anyhow::Result<()> {
   let cli = Cli::parse();
-  let spinner = Spinner::new();
 
-  // -----------------------------------------------------------------------------------------------
-  // 1. Get the current location, either via arguments or via Mullvad API.
+  if let Some(Command::JsonSchema) = cli.command {
+    println!("{:#}", Reporter::json_schema());
+    return Ok(());
+  }
 
-  let location = match cli.latitude.zip(cli.longitude) {
-    | Some((latitude, longitude)) => Coord::new(latitude, longitude),
-    | None => {
-      spinner.set_message("Getting current location");
+  if cli.explain {
+    explain(&cli);
+  }
 
-      let location = Coord::fetch().await?;
+  // Fail before pinging rather than after, as the results would be lost.
+  if cli.output_dir.is_some() && !matches!(cli.format, OutputFormat::Json | OutputFormat::GeoJson) {
+    return Err(ReporterError::ArchiveUnsupported.into());
+  }
 
-      time::sleep(Duration::from_secs(1)).await;
+  let spinner = Spinner::new(cli.spinner);
 
-      location
-    },
+  // -----------------------------------------------------------------------------------------------
+  // 1. Get the targets to ping, if any were supplied. These bypass loading relays entirely.
+
+  let targets = match &cli.targets_file {
+    | Some(path) => relays::read_targets(path)?,
+    | None => cli.targets.clone(),
   };
 
-  // -----------------------------------------------------------------------------------------------
-  // 2. Load relays from file or API and filter them.
+  let (relays, stats, location) = if targets.is_empty() {
+    // ---------------------------------------------------------------------------------------------
+    // 2. Get the current location, either via arguments, environment variables or via Mullvad API.
 
-  spinner.set_message("Loading relays");
+    let coords = cli
+      .latitude
+      .zip(cli.longitude)
+      .map(|(latitude, longitude)| Coord::new(latitude, longitude));
 
-  let loader = RelaysLoader::new(
-    RelaysLoaderConfig { location },
-    vec![
-      Box::new(FilterByDistance::new(cli.distance as f64)),
-      Box::new(FilterByProtocol::new(cli.protocol)),
-    ],
-  );
+    // Flags take precedence over environment variables.
+    let coords = match cli.coords.or(coords) {
+      | Some(coords) => Some(coords),
+      | None => env_location()?,
+    };
+
+    let location = match coords {
+      | Some(location) => location,
+      | None => {
+        spinner.set_message("Getting current location");
+
+        let detected = if cli.no_cache {
+          Coord::detect(cli.location_retries).await?
+        } else {
+          Coord::detect_cached(
+            Duration::from_secs(cli.location_ttl * 60),
+            cli.location_retries,
+          )
+          .await?
+        };
+
+        let location = detected.coord();
+
+        // Distances measured from a VPN exit are meaningless, so at least make it known.
+        if detected.is_mullvad_exit() {
+          if cli.strict_location {
+            return Err(CoordError::LocatedOnMullvad(location).into());
+          }
+
+          spinner.suspend(|| {
+            eprintln!(
+              "Detected location ({location}) is that of the Mullvad relay you are connected \
+               through, so distances will be off. Pass --lat and --lon or disconnect from the VPN \
+               for accurate distances"
+            )
+          });
+        }
+
+        time::sleep(Duration::from_secs(1)).await;
+
+        location
+      },
+    };
+
+    // ---------------------------------------------------------------------------------------------
+    // 3. Load relays from file or API and filter them.
+
+    spinner.set_message("Loading relays");
+
+    let mut filters: Vec<Box<dyn Filter<Item = Relay>>> = vec![
+      Box::new(FilterByRole::new(cli.role.clone())),
+      Box::new(FilterByProtocol::new(resolve_protocols(&cli, &spinner))),
+      Box::new(FilterByFeature::new(cli.feature.clone())),
+    ];
+
+    if cli.owned_only || cli.exclude_owned {
+      filters.push(Box::new(FilterByOwnership::new(cli.owned_only)));
+    }
+
+    if let Some(path) = &cli.exclude_ips_file {
+      filters.push(Box::new(FilterByExcludedIp::new(relays::read_targets(
+        path,
+      )?)));
+    }
+
+    let loader = RelaysLoader::new(
+      RelaysLoaderConfig {
+        location,
+        include_inactive: cli.include_inactive,
+        distance_model: cli.distance_model,
+        relays_file: cli.relays_file.clone(),
+        remote_fallback: !cli.no_remote_fallback,
+        relays_url: cli.relays_url.clone(),
+      },
+      filters,
+    );
+
+    // Show how the download goes, in case relays are loaded from the API.
+    let ticker = tokio::spawn({
+      let spinner = spinner.clone();
+      let progress = loader.progress();
+
+      async move {
+        loop {
+          let kib = |bytes: u64| bytes / 1024;
+
+          match (progress.downloaded(), progress.total()) {
+            | (0, _) => {},
+            | (downloaded, Some(total)) => {
+              spinner.set_message(format!(
+                "Loading relays ({}/{} KiB)",
+                kib(downloaded),
+                kib(total)
+              ));
+            },
+            | (downloaded, None) => {
+              spinner.set_message(format!("Loading relays ({} KiB)", kib(downloaded)));
+            },
+          }
+
+          time::sleep(Duration::from_millis(250)).await;
+        }
+      }
+    });
+
+    let loaded = loader.load().await;
+
+    ticker.abort();
+
+    let (mut relays, mut stats) = loaded?;
+
+    // Whether a relay offers both protocols depends on the others, so this filter runs on the whole
+    // list. Each IP is then kept once, as pinging it twice would measure the same host.
+    if cli.both_protocols {
+      let filter = FilterByMultiProtocol::new(&relays);
+      let mut seen = HashSet::new();
+
+      relays.retain(|relay| filter.matches(relay) && seen.insert(relay.ip.clone()));
+      stats.survived.push((filter.to_string(), relays.len()));
+    }
+
+    // Filter by distance separately, so the radius can be expanded without reloading relays.
+    let mut radius = cli.distance as f64;
+
+    let relays = loop {
+      let filter = distance_filter(&cli, radius);
+      let nearby = relays
+        .iter()
+        .filter(|relay| filter.matches(relay))
+        .cloned()
+        .collect::<Vec<_>>();
+
+      // There's no point in expanding further if every relay is already in range.
+      let capped = filter.distance_km() >= FALLBACK_RADIUS_CAP_KM || nearby.len() == relays.len();
+
+      let enough = cli.min_results.map_or(true, |min| nearby.len() >= min);
+
+      if enough || capped {
+        stats.survived.push((filter.to_string(), nearby.len()));
+        break nearby;
+      }
+
+      radius *= 2.0;
+    };
+
+    if radius > cli.distance as f64 {
+      let unit = if cli.miles { "mi" } else { "km" };
+
+      spinner.suspend(|| {
+        eprintln!(
+          "Expanded the search radius to {} {unit}, found {} relays",
+          radius.round(),
+          relays.len()
+        );
+      });
+    }
+
+    // Keep a fair sample of each protocol, even if one of them dominates nearby.
+    let relays = match cli.nearest_per_protocol {
+      | Some(nearest) => {
+        let relays = nearest_per_protocol(relays, nearest);
+
+        stats.survived.push((
+          format!("proximity (nearest {nearest} per protocol)"),
+          relays.len(),
+        ));
+
+        relays
+      },
+      | None => relays,
+    };
+
+    // A relay file cached long ago may list relays that are gone by now.
+    if cli.warn_stale_relays && matches!(stats.source, RelaysSource::LocalFile(..)) {
+      spinner.set_message("Checking the relay file against the API");
+
+      match loader.find_stale().await {
+        | Ok(stale) if !stale.is_empty() => {
+          let removed = relays
+            .iter()
+            .filter(|relay| stale.is_removed(relay))
+            .map(|relay| relay.ip.as_str())
+            .collect::<Vec<_>>();
+
+          spinner.suspend(|| {
+            eprintln!("{stale}");
+
+            if !removed.is_empty() {
+              eprintln!(
+                "Relays to be pinged that no longer exist: {}",
+                removed.join(", ")
+              );
+            }
+          });
+        },
+        | Ok(..) => {},
+        | Err(err) => {
+          spinner.suspend(|| eprintln!("Couldn't check the relay file against the API: {err:#}"));
+        },
+      }
+    }
+
+    // Only report how many relays made it through the filters, e.g. to tune them quickly.
+    if cli.count_only {
+      spinner.stop();
 
-  let relays = loader.load().await?;
+      let mut countries: HashMap<&str, usize> = HashMap::new();
 
-  time::sleep(Duration::from_secs(1)).await;
+      for relay in &relays {
+        *countries.entry(relay.country.as_str()).or_default() += 1;
+      }
+
+      let mut countries = countries.into_iter().collect::<Vec<_>>();
+      countries.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+
+      println!("{}", relays.len());
+
+      for (country, count) in countries {
+        eprintln!("  {country}: {count}");
+      }
+
+      return Ok(());
+    }
+
+    time::sleep(Duration::from_secs(1)).await;
+
+    (relays, Some(stats), Some(location))
+  } else {
+    spinner.set_message("Resolving targets");
+
+    // Hostnames are resolved once up front, so the timings reflect TCP connects rather than DNS.
+    let mut relays = Vec::new();
+
+    for target in targets {
+      match Relay::resolve(target.as_str()).await {
+        | Ok(relay) => relays.push(relay),
+        | Err(error) => {
+          spinner.suspend(|| eprintln!("Couldn't resolve {target}, skipping: {error}"))
+        },
+      }
+    }
+
+    (relays, None, None)
+  };
 
   if relays.is_empty() {
     spinner.stop();
+
+    // Explain which filter ruled out the relays.
+    if let Some(stats) = &stats {
+      eprintln!("{stats}");
+    }
+
     anyhow::bail!("Couldn't find any relays");
   }
 
   // -----------------------------------------------------------------------------------------------
-  // 3. Ping relays.
+  // 4. Ping relays.
 
-  spinner.set_message("Pinging relays");
+  let margin = cli
+    .margin
+    .map(|margin| {
+      Duration::try_from_secs_f64(margin / 1_000.0)
+        .map_err(|_| anyhow::anyhow!("Invalid margin: {margin} ms"))
+    })
+    .transpose()?;
 
-  let config = Arc::new(
-    RelayPingerConfig::new()
-      .set_count(cli.count)
-      .set_timeout(Duration::from_millis(cli.timeout))
-      .set_interval(Duration::from_millis(cli.interval)),
-  );
+  let config = RelayPingerConfig::new()
+    .set_count(cli.count)
+    .set_margin(margin)
+    .set_max_count(cli.max_count)
+    .set_timeout(Duration::from_millis(cli.timeout))
+    .set_timeout_scale(Duration::from_millis(cli.timeout_scale))
+    .set_interval(Duration::from_millis(cli.interval))
+    .set_connect_retries(cli.connect_retries)
+    .set_socks5(
+      cli
+        .socks5
+        .clone()
+        .or_else(|| cli.tor.then(|| TOR_SOCKS_ADDR.to_string())),
+    )
+    .set_happy_eyeballs(cli.happy_eyeballs)
+    .set_udp(cli.udp)
+    .set_probe_variants(cli.probe_variants)
+    .set_probe(cli.probe)
+    .set_concurrent_load(cli.concurrent_load);
+
+  let overrides = [
+    (
+      Protocol::WireGuard,
+      cli.wg_count,
+      cli.wg_timeout,
+      cli.wg_interval,
+    ),
+    (
+      Protocol::OpenVPN,
+      cli.ovpn_count,
+      cli.ovpn_timeout,
+      cli.ovpn_interval,
+    ),
+  ];
+
+  let mut configs = RelayPingerConfigs::new(config.clone());
+
+  for (protocol, count, timeout, interval) in overrides {
+    if count.is_none() && timeout.is_none() && interval.is_none() {
+      continue;
+    }
+
+    let config = config
+      .clone()
+      .set_count(count.unwrap_or(cli.count))
+      .set_timeout(Duration::from_millis(timeout.unwrap_or(cli.timeout)))
+      .set_interval(Duration::from_millis(interval.unwrap_or(cli.interval)));
+
+    configs = configs.set_override(protocol, config);
+  }
+
+  configs.validate()?;
+
+  let interactive = io::stdin().is_terminal() && io::stderr().is_terminal();
+
+  if relays.len() > RELAYS_SOFT_CAP && interactive && !cli.yes {
+    let confirmed = spinner.suspend(|| confirm_large_sweep(&configs, &relays))?;
+
+    if !confirmed {
+      spinner.stop();
+      anyhow::bail!("Aborted, consider narrowing down the filters");
+    }
+  }
+
+  let expected = configs.expected_duration();
+  let interrupted = Cell::new(false);
+
+  // On the first Ctrl-C stop pinging and report whatever is done. A second one force-quits.
+  let interrupt = async {
+    let _ = signal::ctrl_c().await;
+
+    interrupted.set(true);
+
+    tokio::spawn(async {
+      let _ = signal::ctrl_c().await;
+      process::exit(130);
+    });
+  };
+
+  tokio::pin!(interrupt);
+
+  // Timings of every round merged per relay, in the order relays were first timed.
+  let mut timings: Vec<RelayTimed> = Vec::new();
+  let mut positions: HashMap<RelayKey, usize> = HashMap::new();
+
+  // Exponential moving averages of per-round median RTTs, keyed like the merged timings.
+  let mut emas: HashMap<RelayKey, Duration> = HashMap::new();
+
+  // Relays pinged earlier may benefit from (or pay for) warming up network state, so shuffling
+  // spreads that bias evenly. Results are sorted later anyway.
+  let mut rng = cli.shuffle.then(|| {
+    cli
+      .seed
+      .map_or_else(fastrand::Rng::new, fastrand::Rng::with_seed)
+  });
+
+  for round in 1..=cli.rounds {
+    let mut round_relays = relays.clone();
+
+    if let Some(rng) = &mut rng {
+      rng.shuffle(&mut round_relays);
+    }
+
+    // Filtering happens once all rounds are merged, so nothing is filtered here.
+    let pinger = RelaysPinger::new(round_relays, configs.clone(), Vec::new());
+
+    // Keep the user posted on progress and the estimated time remaining.
+    let progress = pinger.progress();
+    let started = Instant::now();
+    let rounds_left = cli.rounds - round;
+
+    if cli.rounds > 1 {
+      spinner.set_message(format!("Pinging relays (round {round}/{})", cli.rounds));
+    } else {
+      spinner.set_message("Pinging relays");
+    }
+
+    spinner.start_progress(progress.total(), {
+      let progress = progress.clone();
+      move || progress.eta(started.elapsed(), expected) + expected * rounds_left
+    });
+
+    let ticker = tokio::spawn({
+      let spinner = spinner.clone();
+
+      async move {
+        loop {
+          spinner.set_position(progress.done());
+          time::sleep(Duration::from_millis(250)).await;
+        }
+      }
+    });
+
+    let round_results = pinger.ping_until(interrupt.as_mut()).await?;
+
+    ticker.abort();
+
+    for timed in &round_results.kept {
+      if let (Some(alpha), Some(median)) = (cli.ema, timed.rtt_median()) {
+        let key = relay_key(timed.relay());
+        let ema = emas.get(&key).map_or(median, |ema| {
+          median.mul_f64(alpha) + ema.mul_f64(1.0 - alpha)
+        });
+
+        emas.insert(key, ema);
+      }
+    }
+
+    merge_round(&mut timings, &mut positions, round_results.kept);
+
+    if interrupted.get() {
+      break;
+    }
+  }
+
+  let timings = timings
+    .into_iter()
+    .map(|timed| {
+      let ema = emas.get(&relay_key(timed.relay())).copied();
+      timed.with_ema(ema)
+    })
+    .collect();
+
+  let mut filters: Vec<Box<dyn Filter<Item = RelayTimed>>> =
+    vec![Box::new(FilterByRTT::new(cli.rtt, cli.rtt_metric))];
 
-  let pinger = RelaysPinger::new(
-    relays,
-    config,
-    vec![Box::new(FilterByRTT::new(
-      cli.rtt.map(Duration::from_millis),
-    ))],
+  if let Some(jitter) = cli.max_jitter {
+    filters.push(Box::new(FilterByJitter::new(Duration::from_millis(jitter))));
+  }
+
+  // Relays that never responded are hidden first, so they aren't counted as exceeding thresholds.
+  let responsive_filters: Vec<Box<dyn Filter<Item = RelayTimed>>> = if cli.show_unreachable {
+    Vec::new()
+  } else {
+    vec![Box::new(FilterByResponsive)]
+  };
+
+  let responsive = PingResults::filtered(timings, &responsive_filters);
+  let results = PingResults::filtered(responsive.kept, &filters);
+  let unreachable = responsive.filtered_out;
+  let filtered_out = results.filtered_out;
+
+  let (source, mut applied) = match stats {
+    | Some(stats) => {
+      let applied = stats.survived.into_iter().map(|(filter, _)| filter);
+      (stats.source, applied.collect::<Vec<_>>())
+    },
+    | None => (RelaysSource::Targets, Vec::new()),
+  };
+
+  applied.extend(
+    responsive_filters
+      .iter()
+      .chain(&filters)
+      .map(|filter| filter.to_string()),
   );
 
-  let timings = pinger.ping().await?;
+  let report = PingReport::new(results.kept, source)
+    .with_location(location)
+    .with_filters(applied, unreachable + filtered_out);
 
   // -----------------------------------------------------------------------------------------------
-  // 4. Print results.
+  // 5. Print results.
 
   spinner.stop();
 
-  let mut reporter = Reporter::new(timings, cli.sort_by.unwrap_or_default());
+  if interrupted.get() {
+    eprintln!("Interrupted, showing partial results");
+  }
+
+  // The fastest relay is the one with the lowest median RTT, regardless of other sorting options.
+  let sort_by = if cli.fastest {
+    SortBy::MedianRTT
+  } else {
+    cli.sort_by.unwrap_or_default()
+  };
+
+  let location = report.location();
+  let show_hostname = report.iter().any(|timed| timed.relay().hostname.is_some());
+
+  // With fewer pings than required, no median would ever count.
+  let fewest_pings = configs
+    .iter()
+    .map(RelayPingerConfig::count)
+    .min()
+    .unwrap_or(cli.count);
+
+  let min_samples = cli
+    .min_samples_for_median
+    .min(fewest_pings * cli.rounds as usize);
+
+  // The threshold to report as exceeded if no relay lost few enough pings.
+  let loss_exceeded = cli.fail_on_loss.filter(|threshold| {
+    !report
+      .iter()
+      .any(|timed| timed.loss().is_some_and(|loss| loss <= *threshold))
+  });
+
+  let show_target = report.iter().any(|timed| timed.relay().target.is_some());
+
+  let mut reporter = Reporter::new(
+    report,
+    ReporterConfig::new()
+      .set_sort_by(sort_by)
+      .set_score_weights(cli.score)
+      .set_show_target(show_target)
+      .set_show_ipv6(cli.include_ipv6_column)
+      .set_show_hostname(show_hostname)
+      .set_hostname_suffix(cli.hostname_suffix.as_str())
+      .set_min_samples(min_samples)
+      .set_show_country_codes(cli.country_codes)
+      .set_show_family(cli.happy_eyeballs)
+      .set_show_org(cli.show_org)
+      .set_show_features(cli.show_features)
+      .set_show_sort_marker(!cli.no_sort_marker)
+      .set_summary_only(cli.summary_only)
+      .set_show_sparkline(cli.sparkline)
+      .set_show_samples(cli.show_samples)
+      .set_show_sample_count(cli.rounds > 1 || cli.margin.is_some())
+      .set_show_udp(cli.udp)
+      .set_show_ema(cli.ema.is_some())
+      .set_show_variant_spread(cli.probe_variants > 1)
+      .set_table_style(cli.table_style)
+      .set_precision(cli.precision)
+      .set_decimal_separator(cli.decimal_separator)
+      .set_colorize(cli.color.enabled())
+      .set_rtt_thresholds(
+        Duration::from_millis(cli.rtt_good),
+        Duration::from_millis(cli.rtt_warn),
+      ),
+  );
 
   reporter.sort();
-  reporter.report();
+
+  // Distances are compared in kilometers, regardless of the unit the spread is given in.
+  let spread_out = cli.spread.map_or(0, |spread| {
+    if cli.miles {
+      reporter.spread(spread * FilterByDistance::KM_PER_MI)
+    } else {
+      reporter.spread(spread)
+    }
+  });
+
+  if cli.fastest {
+    if !reporter.report_fastest() {
+      anyhow::bail!("Couldn't reach any relays");
+    }
+  } else if cli.emit_mullvad_cmd {
+    if !reporter.report_mullvad_cmd() {
+      anyhow::bail!("Couldn't reach any relays with a known hostname");
+    }
+  } else if cli.format == OutputFormat::Json {
+    let mut stdout = io::stdout().lock();
+
+    reporter.write_json(&mut stdout)?;
+    writeln!(stdout)?;
+  } else if cli.format == OutputFormat::GeoJson {
+    let mut stdout = io::stdout().lock();
+
+    reporter.write_geojson(&mut stdout, location)?;
+    writeln!(stdout)?;
+  } else {
+    match cli.format {
+      | OutputFormat::Plain => reporter.report_plain(),
+      | _ => reporter.report(),
+    }
+
+    if cli.per_country_best {
+      println!();
+      reporter.report_per_country_best();
+    }
+
+    if cli.interactive && interactive {
+      reporter.report_selection()?;
+    }
+
+    if filtered_out > 0 {
+      let thresholds = if cli.max_jitter.is_some() {
+        format!("{} RTT or jitter thresholds", cli.rtt_metric)
+      } else {
+        format!("{} RTT threshold", cli.rtt_metric)
+      };
+
+      eprintln!("{filtered_out} relays didn't pass the {thresholds} and were hidden");
+    }
+
+    if unreachable > 0 {
+      eprintln!(
+        "{unreachable} relays didn't respond and were hidden, use --show-unreachable to list them"
+      );
+    }
+
+    if let Some(spread) = cli.spread.filter(|_| spread_out > 0) {
+      let unit = if cli.miles { "mi" } else { "km" };
+
+      eprintln!(
+        "{spread_out} relays within {spread} {unit} of a higher ranked one were hidden to spread \
+         out the results"
+      );
+    }
+  }
+
+  if let Some(dir) = &cli.output_dir {
+    let path = reporter.archive(dir, cli.format, location)?;
+    eprintln!("Saved results to {}", path.display());
+  }
+
+  #[cfg(feature = "sqlite")]
+  if let Some(path) = &cli.sqlite {
+    let rows = reporter.append_sqlite(path)?;
+    eprintln!("Appended {rows} results to {}", path.display());
+  }
+
+  if let Some(threshold) = loss_exceeded {
+    anyhow::bail!("No relay has a packet loss at or below {threshold}%");
+  }
 
   Ok(())
 }
+
+/// Returns the key identifying the relay across rounds.
+fn relay_key(relay: &Relay) -> RelayKey {
+  (relay.ip.clone(), relay.protocol, relay.role)
+}
+
+/// Merges timings of a round into the ones of previous rounds, keeping relays in the order they
+/// were first timed.
+fn merge_round(
+  timings: &mut Vec<RelayTimed>,
+  positions: &mut HashMap<RelayKey, usize>,
+  round: Vec<RelayTimed>,
+) {
+  for timed in round {
+    match positions.get(&relay_key(timed.relay())) {
+      | Some(&position) => timings[position].merge(timed),
+      | None => {
+        positions.insert(relay_key(timed.relay()), timings.len());
+        timings.push(timed);
+      },
+    }
+  }
+}
+
+/// Resolves protocols given on the command line, inferring `auto` from Mullvad settings. If those
+/// can't be read, any protocol is allowed.
+fn resolve_protocols(cli: &Cli, spinner: &Spinner) -> Vec<Protocol> {
+  let mut protocols = Vec::new();
+
+  for selector in &cli.protocol {
+    match selector {
+      | ProtocolSelector::Only(protocol) => protocols.push(*protocol),
+      | ProtocolSelector::Auto => {
+        let path = cli
+          .mullvad_settings
+          .clone()
+          .or_else(relays::resolve_settings_path);
+
+        let protocol = match path {
+          | Some(path) => relays::read_settings_protocol(&path).map_err(anyhow::Error::from),
+          | None => {
+            Err(anyhow::anyhow!(
+              "Unknown location of the Mullvad settings file"
+            ))
+          },
+        };
+
+        match protocol {
+          | Ok(Some(protocol)) => protocols.push(protocol),
+          | Ok(None) => return Vec::new(),
+          | Err(err) => {
+            spinner.suspend(|| eprintln!("{err:#}, allowing any protocol"));
+            return Vec::new();
+          },
+        }
+      },
+    }
+  }
+
+  protocols
+}
+
+/// Reads the location from the `PINGMOLE_LAT` and `PINGMOLE_LON` environment variables, if both are
+/// set. Handy where passing flags is awkward, e.g. in containers.
+fn env_location() -> anyhow::Result<Option<Coord>> {
+  let latitude = env::var("PINGMOLE_LAT").ok();
+  let longitude = env::var("PINGMOLE_LON").ok();
+
+  match (latitude, longitude) {
+    | (Some(latitude), Some(longitude)) => Ok(Some(format!("{latitude},{longitude}").parse()?)),
+    | (None, None) => Ok(None),
+    | _ => anyhow::bail!("Both PINGMOLE_LAT and PINGMOLE_LON must be set"),
+  }
+}
+
+/// Constructs the distance filter for the given radius, in the units chosen by the user.
+fn distance_filter(cli: &Cli, radius: f64) -> FilterByDistance {
+  if cli.miles {
+    FilterByDistance::new_mi(radius)
+  } else {
+    FilterByDistance::new_km(radius)
+  }
+}
+
+/// Keeps at most `nearest` relays of each protocol, preferring the closest ones. Relays of unknown
+/// distance come last.
+fn nearest_per_protocol(mut relays: Vec<Relay>, nearest: usize) -> Vec<Relay> {
+  relays.sort_by(|a, b| {
+    a.distance
+      .unwrap_or(f64::INFINITY)
+      .total_cmp(&b.distance.unwrap_or(f64::INFINITY))
+  });
+
+  let mut kept: HashMap<Option<Protocol>, usize> = HashMap::new();
+
+  relays.retain(|relay| {
+    let count = kept.entry(relay.protocol).or_default();
+    *count += 1;
+    *count <= nearest
+  });
+
+  relays
+}
+
+/// Warns about pinging a large number of relays and asks the user to confirm.
+fn confirm_large_sweep(configs: &RelayPingerConfigs, relays: &[Relay]) -> io::Result<bool> {
+  let runtime = configs.expected_duration();
+
+  let connects = relays
+    .iter()
+    .map(|relay| configs.for_relay(relay).count())
+    .sum::<usize>();
+
+  eprint!(
+    "About to ping {} relays ({connects} connects in total, ~{} s). Continue? [y/N] ",
+    relays.len(),
+    runtime.as_secs()
+  );
+
+  io::stderr().flush()?;
+
+  let mut answer = String::new();
+  io::stdin().lock().read_line(&mut answer)?;
+
+  Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Prints the effective configuration to stderr.
+fn explain(cli: &Cli) {
+  let targets = !cli.targets.is_empty() || cli.targets_file.is_some();
+
+  let protocol = match cli.protocol.as_slice() {
+    | [] => "any".to_string(),
+    | protocols => {
+      protocols
+        .iter()
+        .map(|protocol| protocol.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+    },
+  };
+
+  let location = if targets {
+    "not needed, pinging targets".to_string()
+  } else if let Some(coords) = &cli.coords {
+    format!("{coords} (--coords)")
+  } else if let Some((latitude, longitude)) = cli.latitude.zip(cli.longitude) {
+    format!("{latitude},{longitude} (--lat/--lon)")
+  } else if let Ok(Some(coords)) = env_location() {
+    format!("{coords} (PINGMOLE_LAT/PINGMOLE_LON)")
+  } else if cli.no_cache {
+    "detected via Mullvad API".to_string()
+  } else {
+    format!(
+      "detected via Mullvad API, cached for {} min",
+      cli.location_ttl
+    )
+  };
+
+  let relays = if let Some(path) = &cli.targets_file {
+    format!("targets from {}", path.display())
+  } else if targets {
+    format!("targets: {}", cli.targets.join(", "))
+  } else if let Some(path) = &cli.relays_file {
+    format!("{} (--relays-file)", path.display())
+  } else if let Some(url) = &cli.relays_url {
+    format!("{url} (--relays-url)")
+  } else {
+    match RelaysLoader::resolve_path() {
+      | Some(path) if path.try_exists().unwrap_or(false) => path.display().to_string(),
+      | _ => "Mullvad API".to_string(),
+    }
+  };
+
+  let unit = if cli.miles { "mi" } else { "km" };
+
+  let expansion = cli.min_results.map_or(String::new(), |min| {
+    format!(", expanded until at least {min} relays are found")
+  });
+
+  eprintln!("Effective configuration:");
+  eprintln!("  distance: < {} {unit}{expansion}", cli.distance);
+  eprintln!("  model:    {}", cli.distance_model);
+  eprintln!("  protocol: {protocol}");
+  eprintln!("  count:    {}", cli.count);
+  eprintln!(
+    "  timeout:  {} ms (+{} ms per 1000 km)",
+    cli.timeout, cli.timeout_scale
+  );
+  eprintln!("  interval: {} ms", cli.interval);
+
+  let overrides = [
+    ("wg", cli.wg_count, cli.wg_timeout, cli.wg_interval),
+    ("ovpn", cli.ovpn_count, cli.ovpn_timeout, cli.ovpn_interval),
+  ];
+
+  for (name, count, timeout, interval) in overrides {
+    let settings = [
+      count.map(|count| format!("count {count}")),
+      timeout.map(|timeout| format!("timeout {timeout} ms")),
+      interval.map(|interval| format!("interval {interval} ms")),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+
+    if !settings.is_empty() {
+      eprintln!("  {:<9} {}", format!("{name}:"), settings.join(", "));
+    }
+  }
+
+  eprintln!("  port:     {PING_PORT}");
+  eprintln!("  probe:    {}", cli.probe);
+
+  if cli.shuffle {
+    let seed = cli
+      .seed
+      .map_or_else(|| "random seed".to_string(), |seed| format!("seed {seed}"));
+
+    eprintln!("  order:    shuffled ({seed})");
+  }
+
+  eprintln!("  location: {location}");
+  eprintln!("  relays:   {relays}");
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn timed(protocol: Protocol, timing: u64) -> RelayTimed {
+    let relay = Relay {
+      protocol: Some(protocol),
+      ..Relay::from_target("127.0.0.1")
+    };
+
+    RelayTimed::new(relay, vec![Duration::from_millis(timing)])
+  }
+
+  #[test]
+  fn merges_rounds_of_relays_sharing_an_ip_separately() {
+    let mut timings = Vec::new();
+    let mut positions = HashMap::new();
+
+    for round in 0..2 {
+      let round = vec![
+        timed(Protocol::WireGuard, 10 + round),
+        timed(Protocol::OpenVPN, 20 + round),
+      ];
+
+      merge_round(&mut timings, &mut positions, round);
+    }
+
+    assert_eq!(timings.len(), 2);
+    assert_eq!(timings[0].relay().protocol, Some(Protocol::WireGuard));
+    assert_eq!(
+      timings[0].timings(),
+      [Duration::from_millis(10), Duration::from_millis(11)]
+    );
+    assert_eq!(timings[1].relay().protocol, Some(Protocol::OpenVPN));
+    assert_eq!(
+      timings[1].timings(),
+      [Duration::from_millis(20), Duration::from_millis(21)]
+    );
+  }
+}
@@ -0,0 +1,79 @@
+//! Synchronous entry points for callers that don't run a tokio runtime. Each call spins up a
+//! current-thread runtime, drives the async pipeline on it to completion and tears it down.
+
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+
+use thiserror::Error;
+use tokio::runtime::Builder;
+
+use crate::pinger::{self, RelayPingerConfig, RelayTimed, RelaysPinger, RelaysPingerError};
+use crate::relays::{Relay, RelaysLoader, RelaysLoaderStats, RelaysSource};
+
+#[derive(Debug, Error)]
+pub enum BlockingError {
+  #[error("Failed to start a runtime")]
+  RuntimeFailed(#[source] io::Error),
+
+  #[error(transparent)]
+  PingFailed(#[from] RelaysPingerError),
+}
+
+/// Runs the given future to completion on a fresh current-thread runtime.
+fn block_on<F: Future>(future: F) -> Result<F::Output, BlockingError> {
+  let runtime = Builder::new_current_thread()
+    .enable_all()
+    .build()
+    .map_err(BlockingError::RuntimeFailed)?;
+
+  Ok(runtime.block_on(future))
+}
+
+/// Blocking version of [`RelaysLoader::load`].
+pub fn load_relays_blocking(
+  loader: &RelaysLoader,
+) -> anyhow::Result<(Vec<Relay>, RelaysLoaderStats)> {
+  block_on(loader.load())?
+}
+
+/// Blocking version of [`RelaysLoader::load_unfiltered`].
+pub fn load_relays_unfiltered_blocking(
+  loader: &RelaysLoader,
+) -> anyhow::Result<(Vec<Relay>, RelaysSource)> {
+  block_on(loader.load_unfiltered())?
+}
+
+/// Blocking version of [`ping_relay`](crate::ping_relay).
+///
+/// ```no_run
+/// use pingmole::{ping_relay_blocking, Relay, RelayPingerConfig};
+///
+/// let relay = Relay::from_target("193.138.218.74");
+/// let timed = ping_relay_blocking(relay, &RelayPingerConfig::new()).unwrap();
+///
+/// println!("{:?}", timed.rtt_median());
+/// ```
+pub fn ping_relay_blocking(
+  relay: Relay,
+  config: &RelayPingerConfig,
+) -> Result<RelayTimed, BlockingError> {
+  block_on(pinger::ping_relay(relay, config))
+}
+
+/// Pings the given relays concurrently and blocks until all of them are done. Timed relays are
+/// returned in the same order as the relays given.
+pub fn ping_relays_blocking(
+  relays: Vec<Relay>,
+  config: &RelayPingerConfig,
+) -> Result<Vec<RelayTimed>, BlockingError> {
+  let config = Arc::new(config.clone());
+
+  block_on(async move {
+    // Pinger tasks are spawned on construction, so this has to happen inside the runtime.
+    let pinger = RelaysPinger::new(relays, config, Vec::new());
+    let results = pinger.ping().await?;
+
+    Ok(results.kept)
+  })?
+}
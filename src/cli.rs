@@ -1,61 +1,532 @@
+use std::env;
+use std::fmt::{self, Display};
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
 use std::time::Duration;
 
 use clap::builder::PossibleValue;
-use clap::{Parser, ValueEnum};
-use indicatif::{ProgressBar, ProgressStyle};
+use clap::{Parser, Subcommand, ValueEnum};
+use indicatif::{ProgressBar, ProgressState, ProgressStyle};
 
-use crate::relays::Protocol;
-use crate::reporter::SortBy;
+use crate::coord::{Coord, DistanceModel};
+use crate::filters::RttThreshold;
+use crate::pinger::{ProbeMode, RttMetric};
+use crate::relays::{Feature, Protocol, RelayRole};
+use crate::reporter::{OutputFormat, ScoreWeights, SortBy, TableStyle, HOSTNAME_SUFFIX};
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true)]
 pub struct Cli {
-  /// Filter servers by used protocol.
+  #[command(subcommand)]
+  pub command: Option<Command>,
+
+  /// Filter servers by used protocol. Can be repeated to allow several protocols. With "auto" the
+  /// protocol configured in the Mullvad app is used.
   #[arg(short, long, value_enum)]
-  pub protocol: Option<Protocol>,
+  pub protocol: Vec<ProtocolSelector>,
+
+  /// Filter servers by role. Can be repeated to allow several roles. Bridges have no protocol of
+  /// their own, so combining them with --protocol leaves none.
+  #[arg(long, value_enum, default_value = "standard")]
+  pub role: Vec<RelayRole>,
+
+  /// Keep only relays offering both OpenVPN and WireGuard on the same IP, so the protocol can be
+  /// switched without changing the endpoint.
+  #[arg(long, conflicts_with = "protocol")]
+  pub both_protocols: bool,
+
+  /// Load relays from this file instead of the one cached by the Mullvad app.
+  #[arg(long, value_name = "PATH", conflicts_with_all = ["targets", "targets_file"])]
+  pub relays_file: Option<PathBuf>,
+
+  /// Fail instead of loading relays from the Mullvad API when the relay file can't be found. The
+  /// location is still detected over the network unless given explicitly.
+  #[arg(long)]
+  pub no_remote_fallback: bool,
+
+  /// Keep only the N closest relays of each protocol, so both protocols get pinged even if one of
+  /// them dominates nearby.
+  #[arg(long, value_name = "N", conflicts_with_all = ["targets", "targets_file"])]
+  pub nearest_per_protocol: Option<usize>,
+
+  /// Compare the local relay file with the relays currently listed by the API and warn about
+  /// differences, e.g. relays that were removed since the file was cached.
+  #[arg(long, conflicts_with_all = ["relays_url", "targets", "targets_file", "no_remote_fallback"])]
+  pub warn_stale_relays: bool,
 
-  /// Filter servers by maximum physical distance (in km).
+  /// Load relays from a file in the same format as the local one, served at this URL, e.g. a
+  /// self-hosted mirror.
+  #[arg(long, value_name = "URL", conflicts_with_all = ["relays_file", "targets", "targets_file"])]
+  pub relays_url: Option<String>,
+
+  /// Set the path to the Mullvad settings file used by --protocol auto.
+  #[arg(long, value_name = "PATH")]
+  pub mullvad_settings: Option<PathBuf>,
+
+  /// Filter servers by supported feature. Can be repeated to require several features.
+  #[arg(long, value_enum)]
+  pub feature: Vec<Feature>,
+
+  /// Keep only servers owned by Mullvad.
+  #[arg(long, conflicts_with = "exclude_owned")]
+  pub owned_only: bool,
+
+  /// Keep only servers rented from partners, i.e. not owned by Mullvad.
+  #[arg(long)]
+  pub exclude_owned: bool,
+
+  /// Filter servers by maximum physical distance (in km, or in miles with --miles).
   #[arg(short, long, default_value_t = 500)]
   pub distance: usize,
 
-  /// Filter servers by maximum rtt (in ms).
-  #[arg(short, long)]
-  pub rtt: Option<u64>,
+  /// Interpret the maximum distance in miles instead of kilometers.
+  #[arg(long)]
+  pub miles: bool,
+
+  /// Exclude relays with IPs listed in the file, one per line. Lines starting with `#` are
+  /// skipped.
+  #[arg(long, value_name = "FILE")]
+  pub exclude_ips_file: Option<PathBuf>,
+
+  /// Set how distances are estimated: straight great-circle or padded to approximate real routing.
+  #[arg(long, value_enum, default_value = "great-circle")]
+  pub distance_model: DistanceModel,
+
+  /// Double the maximum distance until at least this many relays are found. Without it, the
+  /// maximum distance is never exceeded.
+  #[arg(long, value_name = "N")]
+  pub min_results: Option<usize>,
+
+  /// Filter servers by rtt (in ms), e.g. 50, "<50" or ">=100". A bare number means "<=". Compares
+  /// the mean RTT unless --rtt-metric is set.
+  #[arg(short, long, visible_alias = "max-rtt", value_name = "[OP]RTT")]
+  pub rtt: Option<RttThreshold>,
+
+  /// Set the RTT statistic compared against --rtt.
+  #[arg(long, value_enum, default_value = "mean")]
+  pub rtt_metric: RttMetric,
+
+  /// Filter servers by maximum jitter, i.e. the standard deviation of RTTs (in ms).
+  #[arg(long, value_name = "MS")]
+  pub max_jitter: Option<u64>,
+
+  /// Exit with a non-zero code if no remaining relay has a packet loss (in %) at or below this
+  /// threshold. Useful for health checks.
+  #[arg(long, value_name = "PERCENT")]
+  pub fail_on_loss: Option<f64>,
+
+  /// Show relays that didn't respond to any ping. These are hidden by default.
+  #[arg(long)]
+  pub show_unreachable: bool,
+
+  /// Treat median RTTs based on fewer successful pings than this as unknown, so relays that
+  /// answered by luck don't top the table. Capped at the total number of pings.
+  #[arg(long, default_value_t = 2, value_name = "K")]
+  pub min_samples_for_median: usize,
+
+  /// Include relays marked as inactive.
+  #[arg(long)]
+  pub include_inactive: bool,
 
   /// Sort by specified field.
   #[arg(short, long, value_enum)]
   pub sort_by: Option<SortBy>,
 
+  /// Hide relays within this distance of a higher ranked one, for a geographically diverse
+  /// shortlist (in km, or in miles with --miles).
+  #[arg(long, value_name = "DISTANCE")]
+  pub spread: Option<f64>,
+
+  /// Print only the IP of the relay with the lowest median RTT.
+  #[arg(long, conflicts_with = "sort_by")]
+  pub fastest: bool,
+
+  /// Print only the `mullvad` CLI command that selects the top relay.
+  #[arg(long, conflicts_with = "fastest")]
+  pub emit_mullvad_cmd: bool,
+
+  /// Strip this suffix from displayed hostnames, i.e. in the table and `mullvad` commands. JSON
+  /// output keeps full hostnames. Pass an empty string to keep them as they are.
+  #[arg(long, value_name = "SUFFIX", default_value = HOSTNAME_SUFFIX)]
+  pub hostname_suffix: String,
+
+  /// Set the output format. Plain prints one relay per line without a header or box drawing, which
+  /// is easy to process with standard tools. JSON lists relays in the order they were ranked.
+  /// GeoJSON includes the reference location and relay coordinates, e.g. for dropping into
+  /// geojson.io.
+  #[arg(long, value_enum, default_value = "table", conflicts_with_all = ["fastest", "emit_mullvad_cmd"])]
+  pub format: OutputFormat,
+
+  /// Show the IPv6 address of each relay next to the IPv4 one.
+  #[arg(long)]
+  pub include_ipv6_column: bool,
+
+  /// Set the weights used when sorting by score, e.g. "rtt:0.7,distance:0.3".
+  #[arg(long, default_value = "rtt:0.7,distance:0.3")]
+  pub score: ScoreWeights,
+
+  /// Set the number of decimal places in RTT values (in ms). Use 3 for microsecond precision.
+  #[arg(long, default_value_t = 2)]
+  pub precision: usize,
+
+  /// Set the decimal separator used in RTT values and scores, e.g. "," for spreadsheets in locales
+  /// using decimal commas. JSON output is unaffected.
+  #[arg(long, default_value_t = '.', value_name = "CHAR")]
+  pub decimal_separator: char,
+
+  /// Color RTT values green, yellow or red according to --rtt-good and --rtt-warn. With "auto"
+  /// colors are used only if stdout is a terminal and NO_COLOR isn't set.
+  #[arg(long, value_enum, default_value = "auto")]
+  pub color: ColorChoice,
+
+  /// Set the RTT up to which values are colored green (in ms).
+  #[arg(long, default_value_t = 50, value_name = "MS")]
+  pub rtt_good: u64,
+
+  /// Set the RTT up to which values are colored yellow, and above which red (in ms).
+  #[arg(long, default_value_t = 150, value_name = "MS")]
+  pub rtt_warn: u64,
+
+  /// After the results, pick a relay with arrow keys and print the `mullvad` command selecting it.
+  /// Skipped when not running in a terminal.
+  #[arg(long, conflicts_with_all = ["fastest", "emit_mullvad_cmd"])]
+  pub interactive: bool,
+
+  /// After the results, also print the fastest relay of each country.
+  #[arg(long)]
+  pub per_country_best: bool,
+
+  /// Print only a one-line summary (relays that responded, the fastest one and the mean distance)
+  /// instead of the per-relay results, e.g. for notifications.
+  #[arg(long, conflicts_with_all = ["fastest", "emit_mullvad_cmd", "interactive", "per_country_best"])]
+  pub summary_only: bool,
+
+  /// List the timing of each successful ping for each relay, to eyeball the distribution.
+  #[arg(long)]
+  pub show_samples: bool,
+
+  /// Show a sparkline of per-ping timings for each relay. Long runs are downsampled to 32 ticks.
+  #[arg(long)]
+  pub sparkline: bool,
+
+  /// Also save the results to a timestamped file in this directory, creating it if needed. Needs
+  /// --format json or geojson, which sets the format of the file.
+  #[arg(long, value_name = "DIR")]
+  pub output_dir: Option<PathBuf>,
+
+  /// Also append the results to a SQLite database, creating it if needed. Each run's rows share
+  /// the same `run_at` timestamp, so historical performance can be queried with SQL.
+  #[cfg(feature = "sqlite")]
+  #[arg(long, value_name = "FILE")]
+  pub sqlite: Option<PathBuf>,
+
+  /// Set the table border style.
+  #[arg(long, value_enum, default_value = "rounded")]
+  pub table_style: TableStyle,
+
+  /// Don't mark the header of the sorted column with an asterisk.
+  #[arg(long)]
+  pub no_sort_marker: bool,
+
+  /// Show two-letter country codes, e.g. "US", instead of full country names.
+  #[arg(long)]
+  pub country_codes: bool,
+
+  /// Show the organization hosting each relay, as listed in the relay data.
+  #[arg(long)]
+  pub show_org: bool,
+
+  /// Show the features supported by each relay.
+  #[arg(long)]
+  pub show_features: bool,
+
   /// Set the number of pings to perform.
   #[arg(short, long, default_value_t = 8)]
   pub count: usize,
 
+  /// Keep pinging past --count until the standard error of the mean RTT falls under this margin
+  /// (in ms), up to --max-count pings.
+  #[arg(long, value_name = "MS")]
+  pub margin: Option<f64>,
+
+  /// Set the maximum number of pings when --margin is set.
+  #[arg(long, default_value_t = 64, requires = "margin")]
+  pub max_count: usize,
+
+  /// Repeat the whole sweep this many times and aggregate timings across rounds.
+  #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+  pub rounds: u32,
+
+  /// Ping relays in a random order (reshuffled every round), so none is systematically favored by
+  /// its position in the sweep.
+  #[arg(long)]
+  pub shuffle: bool,
+
+  /// Seed the --shuffle order to make it reproducible.
+  #[arg(long, requires = "shuffle")]
+  pub seed: Option<u64>,
+
+  /// Also show an exponential moving average of median RTTs across rounds, weighting the latest
+  /// round by this factor (between 0 and 1). Smooths out noise when using --rounds.
+  #[arg(long, value_name = "ALPHA", value_parser = parse_alpha)]
+  pub ema: Option<f64>,
+
   /// Set the ping timeout (in ms).
   #[arg(long, default_value_t = 750)]
   pub timeout: u64,
 
+  /// Extend the ping timeout for far away relays (in ms per 1000 km of distance), up to
+  /// --interval.
+  #[arg(long, default_value_t = 0)]
+  pub timeout_scale: u64,
+
   /// Set the interval between pings (in ms).
   #[arg(long, default_value_t = 1000)]
   pub interval: u64,
 
-  /// Set the latitude.
+  /// Override --count for WireGuard relays.
+  #[arg(long)]
+  pub wg_count: Option<usize>,
+
+  /// Override --timeout for WireGuard relays (in ms).
+  #[arg(long)]
+  pub wg_timeout: Option<u64>,
+
+  /// Override --interval for WireGuard relays (in ms).
+  #[arg(long)]
+  pub wg_interval: Option<u64>,
+
+  /// Override --count for OpenVPN relays.
+  #[arg(long)]
+  pub ovpn_count: Option<usize>,
+
+  /// Override --timeout for OpenVPN relays (in ms).
+  #[arg(long)]
+  pub ovpn_timeout: Option<u64>,
+
+  /// Override --interval for OpenVPN relays (in ms).
+  #[arg(long)]
+  pub ovpn_interval: Option<u64>,
+
+  /// Set the number of connect retries within a single ping before counting it as lost.
+  #[arg(long, default_value_t = 0)]
+  pub connect_retries: usize,
+
+  /// Set the number of retries when detecting the current location returns no coordinates.
+  #[arg(long, default_value_t = 2)]
+  pub location_retries: usize,
+
+  /// Race IPv4 and IPv6 connects for dual-stack relays and show which address family won.
+  #[arg(long)]
+  pub happy_eyeballs: bool,
+
+  /// Connect to relays through the given SOCKS5 proxy (host:port). RTTs are then measured from the
+  /// proxy's vantage point and include the hop to the proxy.
+  #[arg(long, value_name = "ADDR")]
+  pub socks5: Option<String>,
+
+  /// Connect to relays through the local Tor daemon (SOCKS5 on 127.0.0.1:9050). RTTs then reflect
+  /// the Tor circuit, not your own connection.
+  #[arg(long, conflicts_with = "socks5")]
+  pub tor: bool,
+
+  /// Additionally ping each relay from this many fixed source ports and show the spread between
+  /// them. Reveals path-dependent latency on networks using ECMP.
+  #[arg(long, default_value_t = 0, value_name = "N")]
+  pub probe_variants: usize,
+
+  /// Set what a single ping measures: only the TCP connect, or the connect followed by a small
+  /// request and its reply.
+  #[arg(long, value_enum, default_value = "connect")]
+  pub probe: ProbeMode,
+
+  /// Experimental: keep N background connections to each relay going while pinging it, so RTTs
+  /// reflect how the relay behaves when busy rather than idle. Ignored with --socks5 and --tor.
+  #[arg(long, value_name = "N", default_value_t = 0)]
+  pub concurrent_load: usize,
+
+  /// Also probe the WireGuard port of WireGuard relays over UDP. WireGuard doesn't answer unknown
+  /// peers, so this only tells whether the port is reachable, not how fast.
+  #[arg(long)]
+  pub udp: bool,
+
+  /// Set the latitude and longitude at once, e.g. "36.6534,-78.375".
+  #[arg(long, conflicts_with_all = ["latitude", "longitude"])]
+  pub coords: Option<Coord>,
+
+  /// Set the latitude. Falls back to the PINGMOLE_LAT environment variable.
   #[arg(long = "lat", requires = "longitude", allow_negative_numbers = true)]
   pub latitude: Option<f64>,
 
-  /// Set the longitude.
+  /// Set the longitude. Falls back to the PINGMOLE_LON environment variable.
   #[arg(long = "lon", requires = "latitude", allow_negative_numbers = true)]
   pub longitude: Option<f64>,
+
+  /// Set how long the detected location is cached for (in minutes).
+  #[arg(long, default_value_t = 15)]
+  pub location_ttl: u64,
+
+  /// Don't use or update the cached location.
+  #[arg(long)]
+  pub no_cache: bool,
+
+  /// Fail instead of warning when the detected location is that of a Mullvad relay, i.e. when
+  /// already connected through Mullvad.
+  #[arg(long)]
+  pub strict_location: bool,
+
+  /// Set the progress spinner style. The spinner is disabled automatically if stderr isn't a
+  /// terminal or TERM is "dumb".
+  #[arg(long, value_enum, default_value = "dots")]
+  pub spinner: SpinnerStyle,
+
+  /// Print only the number of relays matching the filters, without pinging them. A breakdown by
+  /// country goes to stderr.
+  #[arg(long, conflicts_with_all = ["targets", "targets_file"])]
+  pub count_only: bool,
+
+  /// Don't ask for confirmation before pinging a large number of relays.
+  #[arg(short, long)]
+  pub yes: bool,
+
+  /// Print the effective configuration before running.
+  #[arg(long)]
+  pub explain: bool,
+
+  /// Ping the given IPs or hostnames instead of loading relays.
+  #[arg(long, value_delimiter = ',', conflicts_with = "targets_file")]
+  pub targets: Vec<String>,
+
+  /// Ping the IPs or hostnames listed in a file (one per line) instead of loading relays.
+  #[arg(long)]
+  pub targets_file: Option<PathBuf>,
+}
+
+/// Commands other than pinging relays, which is what runs without one.
+#[derive(Subcommand, Debug)]
+pub enum Command {
+  /// Print the JSON Schema of the results saved with --output-dir.
+  #[command(hide = true)]
+  JsonSchema,
+}
+
+/// Parses a smoothing factor, which must be within `(0, 1]`.
+fn parse_alpha(s: &str) -> Result<f64, String> {
+  s.parse::<f64>()
+    .ok()
+    .filter(|alpha| *alpha > 0.0 && *alpha <= 1.0)
+    .ok_or_else(|| "must be a number greater than 0 and at most 1".to_string())
+}
+
+/// Protocol given on the command line, either explicitly or to be inferred from Mullvad settings.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProtocolSelector {
+  Only(Protocol),
+  Auto,
+}
+
+impl Display for ProtocolSelector {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      | ProtocolSelector::Only(protocol) => write!(f, "{protocol}"),
+      | ProtocolSelector::Auto => write!(f, "auto (from Mullvad settings)"),
+    }
+  }
+}
+
+impl ValueEnum for ProtocolSelector {
+  fn value_variants<'a>() -> &'a [Self] {
+    &[
+      Self::Only(Protocol::OpenVPN),
+      Self::Only(Protocol::WireGuard),
+      Self::Auto,
+    ]
+  }
+
+  fn to_possible_value(&self) -> Option<PossibleValue> {
+    Some(match self {
+      | ProtocolSelector::Only(Protocol::OpenVPN) => PossibleValue::new("openvpn"),
+      | ProtocolSelector::Only(Protocol::WireGuard) => PossibleValue::new("wireguard"),
+      | ProtocolSelector::Auto => PossibleValue::new("auto"),
+    })
+  }
+}
+
+impl ValueEnum for DistanceModel {
+  fn value_variants<'a>() -> &'a [Self] {
+    &[Self::GreatCircle, Self::Padded]
+  }
+
+  fn to_possible_value(&self) -> Option<PossibleValue> {
+    Some(match self {
+      | DistanceModel::GreatCircle => PossibleValue::new("great-circle"),
+      | DistanceModel::Padded => PossibleValue::new("padded"),
+    })
+  }
+}
+
+impl ValueEnum for Feature {
+  fn value_variants<'a>() -> &'a [Self] {
+    &[Self::Daita]
+  }
+
+  fn to_possible_value(&self) -> Option<PossibleValue> {
+    Some(match self {
+      | Feature::Daita => PossibleValue::new("daita"),
+    })
+  }
+}
+
+impl ValueEnum for RelayRole {
+  fn value_variants<'a>() -> &'a [Self] {
+    &[Self::Standard, Self::Bridge]
+  }
+
+  fn to_possible_value(&self) -> Option<PossibleValue> {
+    Some(match self {
+      | RelayRole::Standard => PossibleValue::new("standard"),
+      | RelayRole::Bridge => PossibleValue::new("bridge"),
+    })
+  }
 }
 
-impl ValueEnum for Protocol {
+impl ValueEnum for RttMetric {
   fn value_variants<'a>() -> &'a [Self] {
-    &[Self::OpenVPN, Self::WireGuard]
+    &[Self::Mean, Self::Median]
   }
 
   fn to_possible_value(&self) -> Option<PossibleValue> {
     Some(match self {
-      | Protocol::OpenVPN => PossibleValue::new("openvpn"),
-      | Protocol::WireGuard => PossibleValue::new("wireguard"),
+      | RttMetric::Mean => PossibleValue::new("mean"),
+      | RttMetric::Median => PossibleValue::new("median"),
+    })
+  }
+}
+
+impl ValueEnum for ProbeMode {
+  fn value_variants<'a>() -> &'a [Self] {
+    &[Self::Connect, Self::Roundtrip]
+  }
+
+  fn to_possible_value(&self) -> Option<PossibleValue> {
+    Some(match self {
+      | ProbeMode::Connect => PossibleValue::new("connect"),
+      | ProbeMode::Roundtrip => PossibleValue::new("roundtrip"),
+    })
+  }
+}
+
+impl ValueEnum for OutputFormat {
+  fn value_variants<'a>() -> &'a [Self] {
+    &[Self::Table, Self::Plain, Self::Json, Self::GeoJson]
+  }
+
+  fn to_possible_value(&self) -> Option<PossibleValue> {
+    Some(match self {
+      | OutputFormat::Table => PossibleValue::new("table"),
+      | OutputFormat::Plain => PossibleValue::new("plain"),
+      | OutputFormat::Json => PossibleValue::new("json"),
+      | OutputFormat::GeoJson => PossibleValue::new("geojson"),
     })
   }
 }
@@ -65,9 +536,13 @@ impl ValueEnum for SortBy {
     &[
       Self::Country,
       Self::City,
+      Self::Hostname,
       Self::MedianRTT,
       Self::MeanRTT,
       Self::Distance,
+      Self::ClosestResponsive,
+      Self::Score,
+      Self::None,
     ]
   }
 
@@ -75,29 +550,170 @@ impl ValueEnum for SortBy {
     Some(match self {
       | SortBy::Country => PossibleValue::new("country"),
       | SortBy::City => PossibleValue::new("city"),
+      | SortBy::Hostname => PossibleValue::new("hostname"),
       | SortBy::MedianRTT => PossibleValue::new("rtt_median"),
       | SortBy::MeanRTT => PossibleValue::new("rtt_mean"),
       | SortBy::Distance => PossibleValue::new("distance"),
+      | SortBy::ClosestResponsive => PossibleValue::new("closest_responsive"),
+      | SortBy::Score => PossibleValue::new("score"),
+      | SortBy::None => PossibleValue::new("none"),
     })
   }
 }
 
-/// Small wrapper around the `indicatif` spinner.
+impl ValueEnum for ColorChoice {
+  fn value_variants<'a>() -> &'a [Self] {
+    &[Self::Auto, Self::Always, Self::Never]
+  }
+
+  fn to_possible_value(&self) -> Option<PossibleValue> {
+    Some(match self {
+      | ColorChoice::Auto => PossibleValue::new("auto"),
+      | ColorChoice::Always => PossibleValue::new("always"),
+      | ColorChoice::Never => PossibleValue::new("never"),
+    })
+  }
+}
+
+impl ValueEnum for SpinnerStyle {
+  fn value_variants<'a>() -> &'a [Self] {
+    &[Self::Dots, Self::Line, Self::None]
+  }
+
+  fn to_possible_value(&self) -> Option<PossibleValue> {
+    Some(match self {
+      | SpinnerStyle::Dots => PossibleValue::new("dots"),
+      | SpinnerStyle::Line => PossibleValue::new("line"),
+      | SpinnerStyle::None => PossibleValue::new("none"),
+    })
+  }
+}
+
+impl ValueEnum for TableStyle {
+  fn value_variants<'a>() -> &'a [Self] {
+    &[
+      Self::Rounded,
+      Self::Sharp,
+      Self::Minimal,
+      Self::Markdown,
+      Self::None,
+    ]
+  }
+
+  fn to_possible_value(&self) -> Option<PossibleValue> {
+    Some(match self {
+      | TableStyle::Rounded => PossibleValue::new("rounded"),
+      | TableStyle::Sharp => PossibleValue::new("sharp"),
+      | TableStyle::Minimal => PossibleValue::new("minimal"),
+      | TableStyle::Markdown => PossibleValue::new("markdown"),
+      | TableStyle::None => PossibleValue::new("none"),
+    })
+  }
+}
+
+/// Whether to use colors in the output.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ColorChoice {
+  #[default]
+  Auto,
+  Always,
+  Never,
+}
+
+impl ColorChoice {
+  /// Resolves the choice, detecting whether colors are welcome in the `auto` case. See
+  /// <https://no-color.org> for `NO_COLOR`.
+  pub fn enabled(self) -> bool {
+    match self {
+      | ColorChoice::Always => true,
+      | ColorChoice::Never => false,
+      | ColorChoice::Auto => {
+        env::var_os("NO_COLOR").map_or(true, |value| value.is_empty()) && io::stdout().is_terminal()
+      },
+    }
+  }
+}
+
+/// Spinner tick styles.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SpinnerStyle {
+  #[default]
+  Dots,
+  Line,
+  None,
+}
+
+/// Small wrapper around the `indicatif` spinner. Clones share the same spinner.
+#[derive(Clone)]
 pub struct Spinner {
   spinner: ProgressBar,
+  /// Tick strings of the chosen style, kept to restyle the spinner into a progress bar.
+  ticks: &'static [&'static str],
 }
 
 impl Spinner {
-  pub fn new() -> Self {
-    let style = ProgressStyle::default_spinner()
-      .tick_strings(&["   ", "·  ", "·· ", "···", " ··", "  ·", "   "]);
+  pub fn new(style: SpinnerStyle) -> Self {
+    // Ticks only make a mess in logs and terminals that can't redraw lines.
+    let is_dumb = env::var("TERM").is_ok_and(|term| term == "dumb");
+
+    if is_dumb || !io::stderr().is_terminal() {
+      return Self::hidden();
+    }
+
+    let ticks: &'static [&'static str] = match style {
+      | SpinnerStyle::Dots => &["   ", "·  ", "·· ", "···", " ··", "  ·", "   "],
+      | SpinnerStyle::Line => &["|", "/", "-", "\\", " "],
+      | SpinnerStyle::None => return Self::hidden(),
+    };
 
+    let style = ProgressStyle::default_spinner().tick_strings(ticks);
     let spinner = ProgressBar::new_spinner();
 
     spinner.set_style(style);
     spinner.enable_steady_tick(Duration::from_millis(150));
 
-    Self { spinner }
+    Self { spinner, ticks }
+  }
+
+  /// Constructs a spinner that draws nothing.
+  fn hidden() -> Self {
+    Self {
+      spinner: ProgressBar::hidden(),
+      ticks: &[],
+    }
+  }
+
+  /// Turns the spinner into a progress bar of the given length, showing the position and the time
+  /// left as estimated by `eta`. Hidden spinners stay hidden.
+  pub fn start_progress<F>(&self, len: usize, eta: F)
+  where
+    F: Fn() -> Duration + Clone + Send + Sync + 'static,
+  {
+    if self.spinner.is_hidden() {
+      return;
+    }
+
+    let Ok(style) =
+      ProgressStyle::with_template("{spinner} {msg} [{bar:20}] {pos}/{len}, ~{eta} left")
+    else {
+      return;
+    };
+
+    let style = style
+      .tick_strings(self.ticks)
+      .progress_chars("=> ")
+      .with_key("eta", move |_: &ProgressState, w: &mut dyn fmt::Write| {
+        let _ = write!(w, "{}s", eta().as_secs());
+      });
+
+    self.spinner.set_style(style);
+    self.spinner.set_length(len as u64);
+    self.spinner.set_position(0);
+  }
+
+  /// Sets the position of the progress bar started with [`Spinner::start_progress`].
+  pub fn set_position(&self, position: usize) {
+    self.spinner.set_position(position as u64);
   }
 
   /// Sets the message of the spinner.
@@ -108,6 +724,14 @@ impl Spinner {
     self.spinner.set_message(message.into());
   }
 
+  /// Hides the spinner while running the given function, e.g. to prompt the user.
+  pub fn suspend<F, R>(&self, f: F) -> R
+  where
+    F: FnOnce() -> R,
+  {
+    self.spinner.suspend(f)
+  }
+
   /// Stops the spinner and clears the message.
   pub fn stop(&self) {
     self.spinner.finish_and_clear();
@@ -116,6 +740,6 @@ impl Spinner {
 
 impl Default for Spinner {
   fn default() -> Self {
-    Self::new()
+    Self::new(SpinnerStyle::default())
   }
 }
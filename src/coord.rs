@@ -1,4 +1,10 @@
-use serde_json::Value;
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -11,6 +17,53 @@ pub enum CoordError {
 
   #[error("Failed to get latitude and longitude from the response")]
   GetCoordsFailed,
+
+  #[error(
+    "Failed to get latitude and longitude: you seem to be connected through Mullvad already. \
+     Either pass --lat and --lon explicitly or disconnect from the VPN first"
+  )]
+  GetCoordsFailedOnMullvad,
+
+  #[error(
+    "The detected location ({0}) is that of the Mullvad relay you are connected through. Either \
+     pass --lat and --lon explicitly or disconnect from the VPN first"
+  )]
+  LocatedOnMullvad(Coord),
+
+  #[error("Invalid coordinates: {0}")]
+  InvalidCoords(String),
+}
+
+/// How distances between coordinates are estimated.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DistanceModel {
+  /// Straight great-circle distance.
+  #[default]
+  GreatCircle,
+  /// Great-circle distance padded to roughly approximate real routing paths.
+  Padded,
+}
+
+impl DistanceModel {
+  /// Factor applied to great-circle distances by the padded model.
+  const PADDING: f64 = 1.3;
+
+  /// Transforms a great-circle distance according to the model.
+  pub fn apply(&self, distance: f64) -> f64 {
+    match self {
+      | DistanceModel::GreatCircle => distance,
+      | DistanceModel::Padded => distance * Self::PADDING,
+    }
+  }
+}
+
+impl Display for DistanceModel {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      | DistanceModel::GreatCircle => write!(f, "great-circle"),
+      | DistanceModel::Padded => write!(f, "padded (x{})", Self::PADDING),
+    }
+  }
 }
 
 /// Represents a point on Earth.
@@ -21,6 +74,9 @@ pub struct Coord {
 }
 
 impl Coord {
+  /// Delay between attempts to fetch the coordinates.
+  const RETRY_DELAY: Duration = Duration::from_millis(500);
+
   /// Constructs a new `Coord`.
   pub fn new(latitude: f64, longitude: f64) -> Self {
     Self {
@@ -29,8 +85,82 @@ impl Coord {
     }
   }
 
-  /// Fetches the current coordinates using the Mullvad API.
-  pub async fn fetch() -> Result<Self, CoordError> {
+  /// Returns the latitude (in degrees).
+  pub fn latitude(&self) -> f64 {
+    self.latitude
+  }
+
+  /// Returns the longitude (in degrees).
+  pub fn longitude(&self) -> f64 {
+    self.longitude
+  }
+
+  /// Fetches the current coordinates using the Mullvad API, retrying up to `retries` times if the
+  /// response comes without them.
+  pub async fn fetch(retries: usize) -> Result<Self, CoordError> {
+    Self::detect(retries).await.map(|detected| detected.coord)
+  }
+
+  /// Fetches the current coordinates, reusing the ones cached on disk if they are younger than
+  /// `ttl` and the public IP hasn't changed since. Freshly fetched coordinates are written to the
+  /// cache.
+  pub async fn fetch_cached(ttl: Duration, retries: usize) -> Result<Self, CoordError> {
+    Self::detect_cached(ttl, retries)
+      .await
+      .map(|detected| detected.coord)
+  }
+
+  /// Same as [`Coord::fetch`], but also tells whether the coordinates belong to a Mullvad exit
+  /// relay.
+  pub async fn detect(retries: usize) -> Result<DetectedLocation, CoordError> {
+    Self::fetch_with_ip(retries)
+      .await
+      .map(|(detected, _)| detected)
+  }
+
+  /// Same as [`Coord::fetch_cached`], but also tells whether the coordinates belong to a Mullvad
+  /// exit relay. Such coordinates are never cached.
+  pub async fn detect_cached(
+    ttl: Duration,
+    retries: usize,
+  ) -> Result<DetectedLocation, CoordError> {
+    if let Some(cache) = LocationCache::read().filter(|cache| cache.is_fresh(ttl)) {
+      // Checking the IP is way cheaper than geolocating it, so it's fine to do it every time.
+      if Self::fetch_ip().await.is_ok_and(|ip| ip == cache.ip) {
+        return Ok(DetectedLocation::new(cache.coord, false));
+      }
+    }
+
+    let (detected, ip) = Self::fetch_with_ip(retries).await?;
+
+    if let Some(ip) = ip.filter(|_| !detected.mullvad_exit) {
+      LocationCache::new(detected.coord, ip).write();
+    }
+
+    Ok(detected)
+  }
+
+  /// Fetches the current coordinates along with the public IP they were resolved for. Right after
+  /// connecting to or disconnecting from a VPN the coordinates may be briefly missing, so such
+  /// responses are retried after a short delay.
+  async fn fetch_with_ip(retries: usize) -> Result<(DetectedLocation, Option<String>), CoordError> {
+    let mut attempt = 0;
+
+    loop {
+      match Self::fetch_once().await {
+        | Err(CoordError::GetCoordsFailed | CoordError::GetCoordsFailedOnMullvad)
+          if attempt < retries =>
+        {
+          attempt += 1;
+          tokio::time::sleep(Self::RETRY_DELAY).await;
+        },
+        | result => return result,
+      }
+    }
+  }
+
+  /// Makes a single attempt to fetch the current coordinates and public IP.
+  async fn fetch_once() -> Result<(DetectedLocation, Option<String>), CoordError> {
     let response = reqwest::get("https://am.i.mullvad.net/json")
       .await
       .map_err(CoordError::FetchFailed)?;
@@ -42,34 +172,214 @@ impl Coord {
 
     let lat = data["latitude"].as_f64();
     let lon = data["longitude"].as_f64();
+    let ip = data["ip"].as_str().map(String::from);
+    let mullvad_exit = data["mullvad_exit_ip"].as_bool() == Some(true);
 
     lat
       .zip(lon)
-      .map(|(latitude, longitude)| Self::new(latitude, longitude))
-      .ok_or_else(|| CoordError::GetCoordsFailed)
+      .map(|(latitude, longitude)| {
+        let coord = Self::new(latitude, longitude);
+        (DetectedLocation::new(coord, mullvad_exit), ip)
+      })
+      .ok_or_else(|| {
+        // Being connected through Mullvad is the most likely reason for missing coordinates.
+        if mullvad_exit {
+          CoordError::GetCoordsFailedOnMullvad
+        } else {
+          CoordError::GetCoordsFailed
+        }
+      })
+  }
+
+  /// Fetches the current public IP.
+  async fn fetch_ip() -> Result<String, CoordError> {
+    let response = reqwest::get("https://am.i.mullvad.net/ip")
+      .await
+      .map_err(CoordError::FetchFailed)?;
+
+    let ip = response
+      .text()
+      .await
+      .map_err(CoordError::ParseResponseFailed)?;
+
+    Ok(ip.trim().to_string())
   }
 
   /// Finds the distance (in kilometers) between two coordinates using the haversine formula.
   pub fn distance_to(&self, other: &Self) -> f64 {
-    // Earth radius in meters. This is *average*, since Earth is not a sphere, but a spheroid.
-    const R: f64 = 6_371_000f64;
+    haversine_km(
+      self.latitude,
+      self.longitude,
+      other.latitude,
+      other.longitude,
+    )
+  }
+}
+
+impl Display for Coord {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{},{}", self.latitude, self.longitude)
+  }
+}
+
+impl FromStr for Coord {
+  type Err = CoordError;
+
+  /// Parses coordinates in the `latitude,longitude` form, e.g. `36.6534,-78.375`.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (latitude, longitude) = s
+      .split_once(',')
+      .ok_or_else(|| CoordError::InvalidCoords("expected latitude,longitude".into()))?;
+
+    let latitude = latitude
+      .trim()
+      .parse::<f64>()
+      .ok()
+      .filter(|latitude| (-90.0..=90.0).contains(latitude))
+      .ok_or_else(|| CoordError::InvalidCoords("latitude must be within -90..90".into()))?;
+
+    let longitude = longitude
+      .trim()
+      .parse::<f64>()
+      .ok()
+      .filter(|longitude| (-180.0..=180.0).contains(longitude))
+      .ok_or_else(|| CoordError::InvalidCoords("longitude must be within -180..180".into()))?;
+
+    Ok(Self::new(latitude, longitude))
+  }
+}
+
+/// Location detected over the network.
+#[derive(Clone, Copy, Debug)]
+pub struct DetectedLocation {
+  /// Detected coordinates.
+  coord: Coord,
+  /// Whether the request went out through a Mullvad exit relay, in which case the coordinates are
+  /// that of the relay rather than the user's.
+  mullvad_exit: bool,
+}
+
+impl DetectedLocation {
+  fn new(coord: Coord, mullvad_exit: bool) -> Self {
+    Self {
+      coord,
+      mullvad_exit,
+    }
+  }
+
+  /// Returns the detected coordinates.
+  pub fn coord(&self) -> Coord {
+    self.coord
+  }
+
+  /// Checks if the coordinates are that of a Mullvad exit relay.
+  pub fn is_mullvad_exit(&self) -> bool {
+    self.mullvad_exit
+  }
+}
+
+/// Location cached on disk between runs.
+#[derive(Debug)]
+struct LocationCache {
+  /// Cached coordinates.
+  coord: Coord,
+  /// Public IP the coordinates were resolved for.
+  ip: String,
+  /// When the coordinates were fetched.
+  fetched_at: SystemTime,
+}
+
+impl LocationCache {
+  fn new(coord: Coord, ip: String) -> Self {
+    Self {
+      coord,
+      ip,
+      fetched_at: SystemTime::now(),
+    }
+  }
 
-    // Turn latitudes and longitudes into radians.
-    let phi1 = self.latitude.to_radians();
-    let phi2 = other.latitude.to_radians();
-    let lam1 = self.longitude.to_radians();
-    let lam2 = other.longitude.to_radians();
+  /// Returns the path to the cache file.
+  fn path() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("pingmole").join("location.json"))
+  }
 
-    // The haversine function. Computes half a versine of the given angle `theta`.
-    let haversine = |theta: f64| (1.0 - theta.cos()) / 2.0;
+  /// Checks if the cache is younger than `ttl`.
+  fn is_fresh(&self, ttl: Duration) -> bool {
+    self.fetched_at.elapsed().is_ok_and(|elapsed| elapsed < ttl)
+  }
 
-    let hav_delta_phi = haversine(phi2 - phi1);
-    let hav_delta_lam = phi1.cos() * phi2.cos() * haversine(lam2 - lam1);
-    let hav_delta = hav_delta_phi + hav_delta_lam;
+  /// Reads the cache. Any failure is treated as a cache miss.
+  fn read() -> Option<Self> {
+    let data = fs::read_to_string(Self::path()?).ok()?;
+    let data = serde_json::from_str::<Value>(&data).ok()?;
 
-    // The distance in meters.
-    let distance = (2.0 * R * hav_delta.sqrt().asin() * 1_000.0).round() / 1_000.0;
+    let coord = Coord::new(data["latitude"].as_f64()?, data["longitude"].as_f64()?);
+    let ip = data["ip"].as_str()?.to_string();
+    let fetched_at = UNIX_EPOCH + Duration::from_secs(data["fetched_at"].as_u64()?);
 
-    distance / 1_000.0
+    Some(Self {
+      coord,
+      ip,
+      fetched_at,
+    })
   }
+
+  /// Writes the cache. This is best effort, so failures are ignored.
+  fn write(&self) {
+    let Some(path) = Self::path() else {
+      return;
+    };
+
+    let fetched_at = self
+      .fetched_at
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_secs();
+
+    let data = json!({
+      "latitude": self.coord.latitude,
+      "longitude": self.coord.longitude,
+      "ip": self.ip,
+      "fetched_at": fetched_at,
+    });
+
+    if let Some(dir) = path.parent() {
+      let _ = fs::create_dir_all(dir);
+    }
+
+    let _ = fs::write(path, data.to_string());
+  }
+}
+
+/// Finds the distance (in kilometers) between two points given by their latitudes and longitudes
+/// (in degrees) using the haversine formula.
+///
+/// ```
+/// // Berlin to Paris is roughly 878 km as the crow flies.
+/// let distance = pingmole::haversine_km(52.5200, 13.4050, 48.8566, 2.3522);
+///
+/// assert!((distance - 878.0).abs() < 1.0);
+/// ```
+pub fn haversine_km(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+  // Earth radius in kilometers. This is *average*, since Earth is not a sphere, but a spheroid.
+  const R: f64 = 6_371f64;
+
+  // Turn latitudes and longitudes into radians.
+  let phi1 = lat1.to_radians();
+  let phi2 = lat2.to_radians();
+  let lam1 = lon1.to_radians();
+  let lam2 = lon2.to_radians();
+
+  // The haversine function. Computes half a versine of the given angle `theta`.
+  let haversine = |theta: f64| (1.0 - theta.cos()) / 2.0;
+
+  let hav_delta_phi = haversine(phi2 - phi1);
+  let hav_delta_lam = phi1.cos() * phi2.cos() * haversine(lam2 - lam1);
+  let hav_delta = hav_delta_phi + hav_delta_lam;
+
+  // The central angle. Using `atan2` instead of `asin` keeps it numerically stable for nearly
+  // antipodal points, where rounding can push `hav_delta` slightly above 1.
+  let theta = 2.0 * hav_delta.sqrt().atan2((1.0 - hav_delta).max(0.0).sqrt());
+
+  R * theta
 }
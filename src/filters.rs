@@ -1,11 +1,14 @@
-use std::fmt::Debug;
+use std::collections::HashSet;
+use std::fmt::{self, Debug, Display};
+use std::str::FromStr;
 use std::time::Duration;
 
-use crate::pinger::RelayTimed;
-use crate::relays::{Protocol, Relay};
+use crate::pinger::{RelayTimed, RttMetric};
+use crate::relays::{Feature, Protocol, Relay, RelayRole};
 
-/// Filter trait to dynamically dispatch filters.
-pub trait Filter: Debug {
+/// Filter trait to dynamically dispatch filters. The `Display` impl should briefly describe the
+/// filter for the user.
+pub trait Filter: Debug + Display {
   type Item;
 
   /// Filter predicate.
@@ -20,53 +23,354 @@ pub struct FilterByDistance {
 }
 
 impl FilterByDistance {
-  pub fn new(distance: f64) -> Self {
+  /// Kilometers in a mile.
+  pub const KM_PER_MI: f64 = 1.609_344;
+
+  /// Constructs a filter with the maximum distance given in kilometers.
+  pub fn new_km(distance: f64) -> Self {
     Self { distance }
   }
+
+  /// Constructs a filter with the maximum distance given in miles.
+  pub fn new_mi(distance: f64) -> Self {
+    Self::new_km(distance * Self::KM_PER_MI)
+  }
+
+  /// Returns the maximum distance in kilometers.
+  pub fn distance_km(&self) -> f64 {
+    self.distance
+  }
 }
 
 impl Filter for FilterByDistance {
   type Item = Relay;
 
   fn matches(&self, relay: &Self::Item) -> bool {
-    relay.distance < self.distance
+    relay
+      .distance
+      .is_some_and(|distance| distance < self.distance)
+  }
+}
+
+impl Display for FilterByDistance {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "distance (< {} km)", self.distance.round())
   }
 }
 
 /// Filter by protocol.
 #[derive(Debug)]
 pub struct FilterByProtocol {
-  /// Protocol to compare with. `None` means any protocol.
-  protocol: Option<Protocol>,
+  /// Protocols to compare with. Empty means any protocol.
+  protocols: Vec<Protocol>,
 }
 
 impl FilterByProtocol {
-  pub fn new(protocol: Option<Protocol>) -> Self {
-    Self { protocol }
+  pub fn new(protocols: Vec<Protocol>) -> Self {
+    Self { protocols }
   }
 }
 
 impl Filter for FilterByProtocol {
   type Item = Relay;
 
+  fn matches(&self, relay: &Self::Item) -> bool {
+    self.protocols.is_empty()
+      || relay
+        .protocol
+        .is_some_and(|protocol| self.protocols.contains(&protocol))
+  }
+}
+
+impl Display for FilterByProtocol {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if self.protocols.is_empty() {
+      return write!(f, "protocol (any)");
+    }
+
+    let protocols = self
+      .protocols
+      .iter()
+      .map(Protocol::to_string)
+      .collect::<Vec<_>>()
+      .join(", ");
+
+    write!(f, "protocol ({protocols})")
+  }
+}
+
+/// Filter by relay role.
+#[derive(Debug)]
+pub struct FilterByRole {
+  /// Roles to compare with. Empty means any role.
+  roles: Vec<RelayRole>,
+}
+
+impl FilterByRole {
+  pub fn new(roles: Vec<RelayRole>) -> Self {
+    Self { roles }
+  }
+}
+
+impl Filter for FilterByRole {
+  type Item = Relay;
+
+  fn matches(&self, relay: &Self::Item) -> bool {
+    self.roles.is_empty() || self.roles.contains(&relay.role)
+  }
+}
+
+impl Display for FilterByRole {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if self.roles.is_empty() {
+      return write!(f, "role (any)");
+    }
+
+    let roles = self
+      .roles
+      .iter()
+      .map(RelayRole::to_string)
+      .collect::<Vec<_>>()
+      .join(", ");
+
+    write!(f, "role ({roles})")
+  }
+}
+
+/// Filter by supported features.
+#[derive(Debug)]
+pub struct FilterByFeature {
+  /// Features a relay must support, all of them. Empty means no requirements.
+  features: Vec<Feature>,
+}
+
+impl FilterByFeature {
+  pub fn new(features: Vec<Feature>) -> Self {
+    Self { features }
+  }
+}
+
+impl Filter for FilterByFeature {
+  type Item = Relay;
+
   fn matches(&self, relay: &Self::Item) -> bool {
     self
-      .protocol
-      .as_ref()
-      .map_or(true, |protocol| relay.protocol == *protocol)
+      .features
+      .iter()
+      .all(|feature| relay.features.contains(feature))
+  }
+}
+
+impl Display for FilterByFeature {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if self.features.is_empty() {
+      return write!(f, "features (any)");
+    }
+
+    let features = self
+      .features
+      .iter()
+      .map(Feature::to_string)
+      .collect::<Vec<_>>()
+      .join(", ");
+
+    write!(f, "features ({features})")
+  }
+}
+
+/// Filter by whether relays are owned by Mullvad or rented from partners.
+#[derive(Debug)]
+pub struct FilterByOwnership {
+  /// Whether to keep Mullvad-owned relays, or rented ones otherwise.
+  owned: bool,
+}
+
+impl FilterByOwnership {
+  pub fn new(owned: bool) -> Self {
+    Self { owned }
+  }
+}
+
+impl Filter for FilterByOwnership {
+  type Item = Relay;
+
+  fn matches(&self, relay: &Self::Item) -> bool {
+    relay.is_mullvad_owned == self.owned
+  }
+}
+
+impl Display for FilterByOwnership {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if self.owned {
+      write!(f, "ownership (Mullvad-owned)")
+    } else {
+      write!(f, "ownership (rented)")
+    }
+  }
+}
+
+/// Filter out relays with the given IPs, e.g. recently used ones.
+#[derive(Debug)]
+pub struct FilterByExcludedIp {
+  /// IPs to exclude.
+  ips: HashSet<String>,
+}
+
+impl FilterByExcludedIp {
+  pub fn new(ips: impl IntoIterator<Item = String>) -> Self {
+    Self {
+      ips: ips.into_iter().collect(),
+    }
+  }
+}
+
+impl Filter for FilterByExcludedIp {
+  type Item = Relay;
+
+  fn matches(&self, relay: &Self::Item) -> bool {
+    !self.ips.contains(&relay.ip)
+      && relay
+        .ipv6
+        .as_ref()
+        .map_or(true, |ipv6| !self.ips.contains(ipv6))
+  }
+}
+
+impl Display for FilterByExcludedIp {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "excluded IPs ({} listed)", self.ips.len())
+  }
+}
+
+/// Filter by support for both OpenVPN and WireGuard on the same IP. Since this depends on other
+/// relays, it's constructed from the whole list of loaded relays.
+#[derive(Debug)]
+pub struct FilterByMultiProtocol {
+  /// IPs advertising both protocols.
+  ips: HashSet<String>,
+}
+
+impl FilterByMultiProtocol {
+  pub fn new(relays: &[Relay]) -> Self {
+    let with = |protocol: Protocol| {
+      relays
+        .iter()
+        .filter(|relay| relay.protocol == Some(protocol))
+        .map(|relay| relay.ip.clone())
+        .collect::<HashSet<_>>()
+    };
+
+    let openvpn = with(Protocol::OpenVPN);
+    let wireguard = with(Protocol::WireGuard);
+
+    Self {
+      ips: openvpn.intersection(&wireguard).cloned().collect(),
+    }
+  }
+}
+
+impl Filter for FilterByMultiProtocol {
+  type Item = Relay;
+
+  fn matches(&self, relay: &Self::Item) -> bool {
+    self.ips.contains(&relay.ip)
+  }
+}
+
+impl Display for FilterByMultiProtocol {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "both protocols")
+  }
+}
+
+/// Comparison operator used by [`FilterByRTT`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Comparison {
+  Less,
+  #[default]
+  LessOrEqual,
+  Greater,
+  GreaterOrEqual,
+}
+
+impl Comparison {
+  /// Compares the given values with this operator.
+  fn holds(self, lhs: Duration, rhs: Duration) -> bool {
+    match self {
+      | Comparison::Less => lhs < rhs,
+      | Comparison::LessOrEqual => lhs <= rhs,
+      | Comparison::Greater => lhs > rhs,
+      | Comparison::GreaterOrEqual => lhs >= rhs,
+    }
+  }
+}
+
+impl Display for Comparison {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      | Comparison::Less => write!(f, "<"),
+      | Comparison::LessOrEqual => write!(f, "<="),
+      | Comparison::Greater => write!(f, ">"),
+      | Comparison::GreaterOrEqual => write!(f, ">="),
+    }
+  }
+}
+
+/// RTT threshold along with the operator to compare measured RTTs against it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RttThreshold {
+  /// Operator to compare with.
+  op: Comparison,
+  /// RTT value to compare with.
+  rtt: Duration,
+}
+
+impl RttThreshold {
+  pub fn new(op: Comparison, rtt: Duration) -> Self {
+    Self { op, rtt }
+  }
+}
+
+impl FromStr for RttThreshold {
+  type Err = String;
+
+  /// Parses thresholds in the `<=50`, `<50`, `>=100` or `>100` form (in ms). A bare number means
+  /// `<=`.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let s = s.trim();
+
+    // Two-character operators go first, so `<=` isn't mistaken for `<`.
+    let (op, value) = [
+      ("<=", Comparison::LessOrEqual),
+      (">=", Comparison::GreaterOrEqual),
+      ("<", Comparison::Less),
+      (">", Comparison::Greater),
+    ]
+    .into_iter()
+    .find_map(|(prefix, op)| s.strip_prefix(prefix).map(|value| (op, value)))
+    .unwrap_or((Comparison::default(), s));
+
+    let rtt = value
+      .trim()
+      .parse::<u64>()
+      .map_err(|_| format!("expected an RTT in ms with an optional <, <=, > or >=, got {s}"))?;
+
+    Ok(Self::new(op, Duration::from_millis(rtt)))
   }
 }
 
 /// Filter by Round-Trip Time.
 #[derive(Debug)]
 pub struct FilterByRTT {
-  /// RTT value to compare with. `None` means any RTT.
-  rtt: Option<Duration>,
+  /// RTT threshold to compare with. `None` means any RTT.
+  threshold: Option<RttThreshold>,
+  /// RTT statistic to compare.
+  metric: RttMetric,
 }
 
 impl FilterByRTT {
-  pub fn new(rtt: Option<Duration>) -> Self {
-    Self { rtt }
+  pub fn new(threshold: Option<RttThreshold>, metric: RttMetric) -> Self {
+    Self { threshold, metric }
   }
 }
 
@@ -74,12 +378,116 @@ impl Filter for FilterByRTT {
   type Item = RelayTimed;
 
   fn matches(&self, timings: &Self::Item) -> bool {
-    // If `rtt` is `None`, then it means any RTT, so we then default to `true`.
-    self.rtt.map_or(true, |filter_rtt| {
-      // Otherwise, we compare the measured RTT with the filter RTT, but here we default to `false`.
+    // If `threshold` is `None`, then it means any RTT, so we then default to `true`.
+    self.threshold.map_or(true, |threshold| {
+      // Otherwise, we compare the measured RTT with the threshold, but here we default to `false`.
       timings
-        .rtt_mean()
-        .map_or(false, |relay_rtt| relay_rtt <= filter_rtt)
+        .rtt(self.metric)
+        .is_some_and(|relay_rtt| threshold.op.holds(relay_rtt, threshold.rtt))
     })
   }
 }
+
+impl Display for FilterByRTT {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.threshold {
+      | Some(RttThreshold { op, rtt }) => {
+        write!(f, "{} RTT ({op} {} ms)", self.metric, rtt.as_millis())
+      },
+      | None => write!(f, "{} RTT (any)", self.metric),
+    }
+  }
+}
+
+/// Filter by responsiveness, i.e. relays that responded to at least one ping.
+#[derive(Debug)]
+pub struct FilterByResponsive;
+
+impl Filter for FilterByResponsive {
+  type Item = RelayTimed;
+
+  fn matches(&self, timings: &Self::Item) -> bool {
+    !timings.timings().is_empty()
+  }
+}
+
+impl Display for FilterByResponsive {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "responsiveness")
+  }
+}
+
+/// Filter by jitter, i.e. the standard deviation of RTTs. Relays that never responded don't match.
+#[derive(Debug)]
+pub struct FilterByJitter {
+  /// Maximum jitter.
+  jitter: Duration,
+}
+
+impl FilterByJitter {
+  pub fn new(jitter: Duration) -> Self {
+    Self { jitter }
+  }
+}
+
+impl Filter for FilterByJitter {
+  type Item = RelayTimed;
+
+  fn matches(&self, timings: &Self::Item) -> bool {
+    timings
+      .rtt_stddev()
+      .is_some_and(|jitter| jitter <= self.jitter)
+  }
+}
+
+impl Display for FilterByJitter {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "jitter (<= {} ms)", self.jitter.as_millis())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn relay_at(distance: f64) -> Relay {
+    Relay {
+      distance: Some(distance),
+      ..Relay::from_target("127.0.0.1")
+    }
+  }
+
+  #[test]
+  fn converts_miles_to_kilometers() {
+    // 100 mi is ~160.9 km.
+    let filter = FilterByDistance::new_mi(100.0);
+
+    assert!(filter.matches(&relay_at(150.0)));
+    assert!(!filter.matches(&relay_at(170.0)));
+  }
+
+  #[test]
+  fn excludes_relays_at_the_limit() {
+    let filter = FilterByDistance::new_km(160.0);
+
+    assert!(filter.matches(&relay_at(159.9)));
+    assert!(!filter.matches(&relay_at(160.0)));
+    assert!(!filter.matches(&Relay::from_target("127.0.0.1")));
+  }
+
+  fn timed(timings: &[u64]) -> RelayTimed {
+    let timings = timings.iter().copied().map(Duration::from_millis).collect();
+
+    RelayTimed::new(Relay::from_target("127.0.0.1"), timings)
+  }
+
+  #[test]
+  fn drops_relays_with_high_jitter() {
+    let filter = FilterByJitter::new(Duration::from_millis(20));
+
+    // Standard deviation of 40 ms around a mean of 50 ms.
+    assert!(!filter.matches(&timed(&[10, 10, 90, 90])));
+    assert!(filter.matches(&timed(&[20, 21, 19, 20])));
+    assert!(!filter.matches(&timed(&[])));
+  }
+}
@@ -1,9 +1,44 @@
+//! CLI that helps to filter Mullvad servers and pick the closest one.
+//!
+//! Besides the CLI, a few building blocks are exposed for use in other tools, e.g. measuring a
+//! single relay:
+//!
+//! ```no_run
+//! use pingmole::{ping_relay, Relay, RelayPingerConfig};
+//!
+//! # async fn example() {
+//! let relay = Relay::from_target("193.138.218.74");
+//! let timed = ping_relay(relay, &RelayPingerConfig::new()).await;
+//!
+//! println!("{:?}", timed.rtt_median());
+//! # }
+//! ```
+//!
+//! With the `blocking` feature enabled, synchronous counterparts such as [`ping_relays_blocking`]
+//! are available for callers that don't run a tokio runtime.
+
 #![allow(clippy::enum_variant_names)]
 
 pub mod app;
+#[cfg(feature = "blocking")]
+pub(crate) mod blocking;
 pub(crate) mod cli;
 pub(crate) mod coord;
 pub(crate) mod filters;
 pub(crate) mod pinger;
 pub(crate) mod relays;
 pub(crate) mod reporter;
+
+#[cfg(feature = "blocking")]
+pub use blocking::{
+  load_relays_blocking, load_relays_unfiltered_blocking, ping_relay_blocking, ping_relays_blocking,
+  BlockingError,
+};
+pub use coord::{haversine_km, Coord, CoordError, DetectedLocation, DistanceModel};
+pub use pinger::{
+  ping_relay, PingProgress, PingResults, ProbeMode, RelayPingerConfig, RelayPingerConfigError,
+  RelayPingerConfigs, RelayTimed, RelaysPinger, RelaysPingerError, RttMetric, UdpStatus,
+};
+pub use relays::{
+  Feature, Protocol, Relay, RelayRole, RelaysError, RelaysLoader, RelaysLoaderConfig, RelaysSource,
+};
@@ -1,27 +1,111 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::future::{self, Future};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::SystemTime;
+use std::{slice, vec};
 
+use socket2::SockRef;
 use thiserror::Error;
-use tokio::net::TcpStream;
-use tokio::task::JoinHandle;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{self as net, TcpSocket, TcpStream, UdpSocket};
+use tokio::task::{self, JoinHandle, JoinSet};
 use tokio::time::{self, Duration, Instant, MissedTickBehavior};
+use tokio_socks::tcp::Socks5Stream;
 
+use crate::coord::Coord;
 use crate::filters::Filter;
-use crate::relays::Relay;
+use crate::relays::{Protocol, Relay, RelaysSource};
+
+/// Port to connect to when pinging. I'm not entirely sure about hardcoding port 80, but it seems to
+/// be open on servers I checked.
+pub const PING_PORT: u16 = 80;
+
+/// Default WireGuard port to probe over UDP.
+pub const WIREGUARD_PORT: u16 = 51820;
+
+/// Pause between background connections made to put a relay under load.
+const LOAD_PAUSE: Duration = Duration::from_millis(50);
+
+/// Request sent in the roundtrip mode. The ping port usually speaks HTTP, so a minimal request is
+/// the most likely to get an answer.
+const ROUNDTRIP_REQUEST: &[u8] = b"HEAD / HTTP/1.0\r\n\r\n";
+
+/// Size of a WireGuard handshake initiation message.
+const HANDSHAKE_INITIATION_LEN: usize = 148;
 
 #[derive(Debug, Error)]
 pub enum RelaysPingerError {
   #[error("Failed to await a task")]
   PingerAwaitFailed,
+
+  /// Only possible when built with unwinding panics. The binary aborts on panic in release builds,
+  /// so this is mostly for library users.
+  #[error("Pinging {ip} panicked")]
+  PingerPanicked { ip: String },
+
+  #[error("Pinging {ip} was cancelled")]
+  PingerCancelled { ip: String },
 }
 
-#[derive(Debug)]
+#[derive(Debug, Error)]
+pub enum RelayPingerConfigError {
+  #[error("Ping count must be at least 1")]
+  ZeroCount,
+
+  #[error("Ping timeout must be greater than zero")]
+  ZeroTimeout,
+
+  #[error("Ping timeout ({timeout:?}) must not exceed the interval between pings ({interval:?})")]
+  TimeoutExceedsInterval {
+    timeout: Duration,
+    interval: Duration,
+  },
+}
+
+#[derive(Clone, Debug)]
 pub struct RelayPingerConfig {
-  /// How many times to ping the relay. Defaults to 8.
+  /// How many times to ping the relay. With a margin set, this is the minimum. Defaults to 8.
   count: usize,
+  /// Target standard error of the mean RTT. If set, pinging continues past `count` until the
+  /// standard error falls under it or `max_count` is reached. Defaults to `None`.
+  margin: Option<Duration>,
+  /// Maximum number of pings when a margin is set. Defaults to 64.
+  max_count: usize,
   /// How long to wait before timing out a ping. Defaults to 750 ms.
   timeout: Duration,
+  /// How much to extend the timeout per 1000 km of distance to the relay, so far away relays are
+  /// not unfairly timed out. The extended timeout is capped at the interval. Defaults to 0.
+  timeout_scale: Duration,
   /// How long to wait between pings. Defaults to 1 second.
   interval: Duration,
+  /// How many times to retry connecting within a single ping before counting it as a miss.
+  /// Defaults to 0.
+  connect_retries: usize,
+  /// SOCKS5 proxy to connect through. Note that RTTs then include the hop to the proxy, i.e. they
+  /// are measured from the proxy's vantage point. Defaults to `None`.
+  socks5: Option<String>,
+  /// Whether to race IPv4 and IPv6 connects for dual-stack relays, Happy Eyeballs style. Defaults
+  /// to `false`.
+  happy_eyeballs: bool,
+  /// Whether to also probe the WireGuard port of WireGuard relays over UDP. Defaults to `false`.
+  udp: bool,
+  /// Number of fixed source ports to additionally ping from, to detect latency differences between
+  /// paths on networks using ECMP. Defaults to 0, i.e. disabled.
+  probe_variants: usize,
+  /// Whether to record when each successful ping was started, for time-series analysis. Defaults
+  /// to `false`.
+  record_timestamps: bool,
+  /// What a single ping measures. Defaults to [`ProbeMode::Connect`].
+  probe: ProbeMode,
+  /// TCP port to ping. Defaults to [`PING_PORT`].
+  port: u16,
+  /// Number of background connections to keep opening to the relay while pinging it, to measure
+  /// it under some pressure. Experimental. Defaults to 0, i.e. disabled.
+  concurrent_load: usize,
 }
 
 impl RelayPingerConfig {
@@ -29,31 +113,345 @@ impl RelayPingerConfig {
     Self::default()
   }
 
+  /// Returns the number of pings to send.
+  pub fn count(&self) -> usize {
+    self.count
+  }
+
+  /// Estimates how long pinging a single relay takes. Since relays are pinged concurrently, this is
+  /// also roughly how long pinging all of them takes.
+  pub fn expected_duration(&self) -> Duration {
+    let mut expected = self.interval * self.count.saturating_sub(1) as u32 + self.timeout;
+
+    // The UDP probe waits for the whole timeout on silent relays.
+    if self.udp {
+      expected += self.timeout;
+    }
+
+    // Variants are probed after the pings, connecting back to back up to the timeout each.
+    if self.socks5.is_none() {
+      expected += self.timeout * (self.probe_variants * self.count) as u32;
+    }
+
+    expected
+  }
+
+  /// Checks the config for nonsensical combinations of settings.
+  pub fn validate(&self) -> Result<(), RelayPingerConfigError> {
+    if self.count == 0 {
+      return Err(RelayPingerConfigError::ZeroCount);
+    }
+
+    if self.timeout.is_zero() {
+      return Err(RelayPingerConfigError::ZeroTimeout);
+    }
+
+    // With a single ping there's nothing to overlap with, so the interval doesn't matter.
+    if self.count > 1 && self.timeout > self.interval {
+      return Err(RelayPingerConfigError::TimeoutExceedsInterval {
+        timeout: self.timeout,
+        interval: self.interval,
+      });
+    }
+
+    Ok(())
+  }
+
+  /// Validates the config and returns it, so it can end a chain of setters.
+  pub fn build(self) -> Result<Self, RelayPingerConfigError> {
+    self.validate().map(|_| self)
+  }
+
   /// Set the number of pings to send.
   pub fn set_count(mut self, count: usize) -> Self {
     self.count = count;
     self
   }
 
+  /// Set the target standard error of the mean RTT.
+  pub fn set_margin(mut self, margin: Option<Duration>) -> Self {
+    self.margin = margin;
+    self
+  }
+
+  /// Set the maximum number of pings when a margin is set.
+  pub fn set_max_count(mut self, max_count: usize) -> Self {
+    self.max_count = max_count;
+    self
+  }
+
   /// Set the timeout for each ping.
   pub fn set_timeout(mut self, timeout: Duration) -> Self {
     self.timeout = timeout;
     self
   }
 
+  /// Set how much to extend the timeout per 1000 km of distance to the relay.
+  pub fn set_timeout_scale(mut self, timeout_scale: Duration) -> Self {
+    self.timeout_scale = timeout_scale;
+    self
+  }
+
   /// Set the interval between pings.
   pub fn set_interval(mut self, interval: Duration) -> Self {
     self.interval = interval;
     self
   }
+
+  /// Set the number of connect retries within a single ping.
+  pub fn set_connect_retries(mut self, connect_retries: usize) -> Self {
+    self.connect_retries = connect_retries;
+    self
+  }
+
+  /// Set the SOCKS5 proxy to connect through.
+  pub fn set_socks5(mut self, socks5: Option<String>) -> Self {
+    self.socks5 = socks5;
+    self
+  }
+
+  /// Set whether to race IPv4 and IPv6 connects for dual-stack relays.
+  pub fn set_happy_eyeballs(mut self, happy_eyeballs: bool) -> Self {
+    self.happy_eyeballs = happy_eyeballs;
+    self
+  }
+
+  /// Set whether to probe the WireGuard port of WireGuard relays over UDP.
+  pub fn set_udp(mut self, udp: bool) -> Self {
+    self.udp = udp;
+    self
+  }
+
+  /// Set the number of fixed source ports to additionally ping from.
+  pub fn set_probe_variants(mut self, probe_variants: usize) -> Self {
+    self.probe_variants = probe_variants;
+    self
+  }
+
+  /// Set whether to record when each successful ping was started.
+  pub fn set_record_timestamps(mut self, record_timestamps: bool) -> Self {
+    self.record_timestamps = record_timestamps;
+    self
+  }
+
+  /// Set what a single ping measures.
+  pub fn set_probe(mut self, probe: ProbeMode) -> Self {
+    self.probe = probe;
+    self
+  }
+
+  /// Set the TCP port to ping.
+  pub fn set_port(mut self, port: u16) -> Self {
+    self.port = port;
+    self
+  }
+
+  /// Set the number of background connections to keep opening to the relay while pinging it.
+  pub fn set_concurrent_load(mut self, concurrent_load: usize) -> Self {
+    self.concurrent_load = concurrent_load;
+    self
+  }
 }
 
 impl Default for RelayPingerConfig {
   fn default() -> Self {
     Self {
       count: 8,
+      margin: None,
+      max_count: 64,
       timeout: Duration::from_millis(750),
+      timeout_scale: Duration::ZERO,
       interval: Duration::from_millis(1_000),
+      connect_retries: 0,
+      socks5: None,
+      happy_eyeballs: false,
+      udp: false,
+      probe_variants: 0,
+      record_timestamps: false,
+      probe: ProbeMode::default(),
+      port: PING_PORT,
+      concurrent_load: 0,
+    }
+  }
+}
+
+/// Ping configs to use for relays, with optional overrides for relays of a given protocol.
+#[derive(Clone, Debug)]
+pub struct RelayPingerConfigs {
+  /// Config for relays without an override.
+  default: Arc<RelayPingerConfig>,
+  /// Config for WireGuard relays, if overridden.
+  wireguard: Option<Arc<RelayPingerConfig>>,
+  /// Config for OpenVPN relays, if overridden.
+  openvpn: Option<Arc<RelayPingerConfig>>,
+}
+
+impl RelayPingerConfigs {
+  pub fn new(default: RelayPingerConfig) -> Self {
+    Self {
+      default: Arc::new(default),
+      wireguard: None,
+      openvpn: None,
+    }
+  }
+
+  /// Set the config to use for relays of the given protocol.
+  pub fn set_override(mut self, protocol: Protocol, config: RelayPingerConfig) -> Self {
+    let config = Some(Arc::new(config));
+
+    match protocol {
+      | Protocol::WireGuard => self.wireguard = config,
+      | Protocol::OpenVPN => self.openvpn = config,
+    }
+
+    self
+  }
+
+  /// Returns the config to use for the given relay.
+  pub fn for_relay(&self, relay: &Relay) -> Arc<RelayPingerConfig> {
+    let config = match relay.protocol {
+      | Some(Protocol::WireGuard) => self.wireguard.as_ref(),
+      | Some(Protocol::OpenVPN) => self.openvpn.as_ref(),
+      | None => None,
+    };
+
+    Arc::clone(config.unwrap_or(&self.default))
+  }
+
+  /// Iterates over the default config and all overrides.
+  pub fn iter(&self) -> impl Iterator<Item = &RelayPingerConfig> {
+    [
+      Some(&self.default),
+      self.wireguard.as_ref(),
+      self.openvpn.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|config| config.as_ref())
+  }
+
+  /// Estimates how long pinging the relays takes, which is as long as the slowest config takes.
+  pub fn expected_duration(&self) -> Duration {
+    self
+      .iter()
+      .map(RelayPingerConfig::expected_duration)
+      .max()
+      .unwrap_or_default()
+  }
+
+  /// Validates all configs.
+  pub fn validate(&self) -> Result<(), RelayPingerConfigError> {
+    self.iter().try_for_each(RelayPingerConfig::validate)
+  }
+}
+
+impl From<RelayPingerConfig> for RelayPingerConfigs {
+  fn from(config: RelayPingerConfig) -> Self {
+    Self::new(config)
+  }
+}
+
+impl From<Arc<RelayPingerConfig>> for RelayPingerConfigs {
+  fn from(config: Arc<RelayPingerConfig>) -> Self {
+    Self {
+      default: config,
+      wireguard: None,
+      openvpn: None,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum AddressFamily {
+  IPv4,
+  IPv6,
+}
+
+impl AddressFamily {
+  /// Returns the family of the given address, if it's an IP address at all.
+  fn of(addr: &str) -> Option<Self> {
+    addr.parse::<IpAddr>().ok().map(|ip| {
+      match ip {
+        | IpAddr::V4(..) => Self::IPv4,
+        | IpAddr::V6(..) => Self::IPv6,
+      }
+    })
+  }
+}
+
+/// Joins a host and a port into an address to connect to, wrapping IPv6 addresses in brackets.
+fn host_port(host: &str, port: u16) -> String {
+  match host.parse::<IpAddr>() {
+    | Ok(ip) => SocketAddr::new(ip, port).to_string(),
+    | Err(..) => format!("{host}:{port}"),
+  }
+}
+
+impl Display for AddressFamily {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      | AddressFamily::IPv4 => write!(f, "IPv4"),
+      | AddressFamily::IPv6 => write!(f, "IPv6"),
+    }
+  }
+}
+
+/// Outcome of probing the WireGuard port over UDP. WireGuard stays silent unless the handshake
+/// comes from a known peer, so silence is the expected outcome for a reachable relay, while an
+/// ICMP error means the port is unreachable.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UdpStatus {
+  /// Something replied.
+  Replied,
+  /// The probe was sent and nothing came back, i.e. the port is open or filtered.
+  Silent,
+  /// The probe couldn't be sent or an ICMP error came back.
+  Unreachable,
+}
+
+impl Display for UdpStatus {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      | UdpStatus::Replied => write!(f, "replied"),
+      | UdpStatus::Silent => write!(f, "silent"),
+      | UdpStatus::Unreachable => write!(f, "unreachable"),
+    }
+  }
+}
+
+/// What a single ping measures.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ProbeMode {
+  /// Time until the TCP connection is established, i.e. a SYN and SYN/ACK round trip.
+  #[default]
+  Connect,
+  /// Time until the connection is established, a small request is sent, and a response, a close
+  /// or a reset comes back.
+  Roundtrip,
+}
+
+impl Display for ProbeMode {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      | ProbeMode::Connect => write!(f, "connect"),
+      | ProbeMode::Roundtrip => write!(f, "roundtrip"),
+    }
+  }
+}
+
+/// RTT statistic to use where a single value per relay is needed.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RttMetric {
+  #[default]
+  Mean,
+  Median,
+}
+
+impl Display for RttMetric {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      | RttMetric::Mean => write!(f, "mean"),
+      | RttMetric::Median => write!(f, "median"),
     }
   }
 }
@@ -64,11 +462,122 @@ pub struct RelayTimed {
   relay: Relay,
   /// Relay timings.
   timings: Vec<Duration>,
+  /// Number of pings attempted, including lost ones.
+  attempts: usize,
+  /// Address families used by successful pings, where known.
+  families: Vec<AddressFamily>,
+  /// Outcome of the UDP probe, if one was made.
+  udp: Option<UdpStatus>,
+  /// Median RTTs of pings made from fixed source ports, one per port that got any response.
+  variants: Vec<Duration>,
+  /// Start times of successful pings along with their timings, if recording them was enabled.
+  samples: Vec<(SystemTime, Duration)>,
+  /// Exponential moving average of median RTTs across rounds, if computed.
+  ema: Option<Duration>,
+  /// What the timings measure.
+  probe: ProbeMode,
 }
 
 impl RelayTimed {
   pub fn new(relay: Relay, timings: Vec<Duration>) -> Self {
-    Self { relay, timings }
+    Self {
+      relay,
+      attempts: timings.len(),
+      timings,
+      families: Vec::new(),
+      udp: None,
+      variants: Vec::new(),
+      samples: Vec::new(),
+      ema: None,
+      probe: ProbeMode::default(),
+    }
+  }
+
+  /// Sets the number of pings attempted, including lost ones.
+  pub fn with_attempts(mut self, attempts: usize) -> Self {
+    self.attempts = attempts;
+    self
+  }
+
+  /// Sets the address families used by successful pings.
+  pub fn with_families(mut self, families: Vec<AddressFamily>) -> Self {
+    self.families = families;
+    self
+  }
+
+  /// Sets the outcome of the UDP probe.
+  pub fn with_udp(mut self, udp: Option<UdpStatus>) -> Self {
+    self.udp = udp;
+    self
+  }
+
+  /// Sets the median RTTs of pings made from fixed source ports.
+  pub fn with_variants(mut self, variants: Vec<Duration>) -> Self {
+    self.variants = variants;
+    self
+  }
+
+  /// Sets the start times of successful pings along with their timings.
+  pub fn with_samples(mut self, samples: Vec<(SystemTime, Duration)>) -> Self {
+    self.samples = samples;
+    self
+  }
+
+  /// Sets the exponential moving average of median RTTs across rounds.
+  pub fn with_ema(mut self, ema: Option<Duration>) -> Self {
+    self.ema = ema;
+    self
+  }
+
+  /// Sets what the timings measure.
+  pub fn with_probe(mut self, probe: ProbeMode) -> Self {
+    self.probe = probe;
+    self
+  }
+
+  /// Returns what the timings measure.
+  pub fn probe(&self) -> ProbeMode {
+    self.probe
+  }
+
+  /// Returns the exponential moving average of median RTTs across rounds, if computed.
+  pub fn ema(&self) -> Option<Duration> {
+    self.ema
+  }
+
+  /// Returns the start times of successful pings along with their timings. Empty unless recording
+  /// them was enabled in the config.
+  pub fn samples(&self) -> &[(SystemTime, Duration)] {
+    &self.samples
+  }
+
+  /// Returns the median RTTs of pings made from fixed source ports.
+  pub fn variants(&self) -> &[Duration] {
+    &self.variants
+  }
+
+  /// Gets the difference between the slowest and the fastest source port variant. Needs at least
+  /// two variants to be meaningful.
+  pub fn variant_spread(&self) -> Option<Duration> {
+    let min = self.variants.iter().min()?;
+    let max = self.variants.iter().max()?;
+
+    (self.variants.len() > 1).then(|| *max - *min)
+  }
+
+  /// Returns the outcome of the UDP probe, if one was made.
+  pub fn udp(&self) -> Option<UdpStatus> {
+    self.udp
+  }
+
+  /// Merges timings from another round of pinging the same relay.
+  pub fn merge(&mut self, other: RelayTimed) {
+    self.timings.extend(other.timings);
+    self.attempts += other.attempts;
+    self.families.extend(other.families);
+    self.udp = other.udp.or(self.udp);
+    self.variants.extend(other.variants);
+    self.samples.extend(other.samples);
   }
 
   /// Returns the relay.
@@ -76,6 +585,32 @@ impl RelayTimed {
     &self.relay
   }
 
+  /// Returns the timings of successful pings, in the order they were made.
+  pub fn timings(&self) -> &[Duration] {
+    &self.timings
+  }
+
+  /// Returns the address families used by successful pings, where known.
+  pub fn families(&self) -> &[AddressFamily] {
+    &self.families
+  }
+
+  /// Gets the share of attempted pings that got no response, in percent.
+  pub fn loss(&self) -> Option<f64> {
+    match self.attempts {
+      | 0 => None,
+      | attempts => Some((attempts - self.timings.len()) as f64 / attempts as f64 * 100.0),
+    }
+  }
+
+  /// Gets the RTT according to the given metric.
+  pub fn rtt(&self, metric: RttMetric) -> Option<Duration> {
+    match metric {
+      | RttMetric::Mean => self.rtt_mean(),
+      | RttMetric::Median => self.rtt_median(),
+    }
+  }
+
   /// Gets the mean RTT.
   pub fn rtt_mean(&self) -> Option<Duration> {
     match self.timings.len() {
@@ -84,26 +619,96 @@ impl RelayTimed {
     }
   }
 
-  /// Gets the median RTT.
+  /// Gets the standard deviation of RTTs, i.e. the jitter.
+  ///
+  /// ```
+  /// use std::time::Duration;
+  ///
+  /// use pingmole::{Relay, RelayTimed};
+  ///
+  /// let timed = |millis: &[u64]| {
+  ///   let timings = millis.iter().copied().map(Duration::from_millis).collect();
+  ///   RelayTimed::new(Relay::from_target("127.0.0.1"), timings)
+  /// };
+  ///
+  /// assert_eq!(timed(&[]).rtt_stddev(), None);
+  /// assert_eq!(timed(&[20, 20, 20]).rtt_stddev(), Some(Duration::ZERO));
+  /// assert_eq!(timed(&[10, 10, 90, 90]).rtt_stddev(), Some(Duration::from_millis(40)));
+  /// ```
+  pub fn rtt_stddev(&self) -> Option<Duration> {
+    let mean = self.rtt_mean()?.as_secs_f64();
+
+    let variance = self
+      .timings
+      .iter()
+      .map(|timing| (timing.as_secs_f64() - mean).powi(2))
+      .sum::<f64>()
+      / self.timings.len() as f64;
+
+    Some(Duration::from_secs_f64(variance.sqrt()))
+  }
+
+  /// Gets the median RTT. With an even number of timings it's the mean of the two middle ones.
+  ///
+  /// ```
+  /// use std::time::Duration;
+  ///
+  /// use pingmole::{Relay, RelayTimed};
+  ///
+  /// let timed = |millis: &[u64]| {
+  ///   let timings = millis.iter().copied().map(Duration::from_millis).collect();
+  ///   RelayTimed::new(Relay::from_target("127.0.0.1"), timings)
+  /// };
+  ///
+  /// assert_eq!(timed(&[]).rtt_median(), None);
+  /// assert_eq!(timed(&[7]).rtt_median(), Some(Duration::from_millis(7)));
+  /// assert_eq!(timed(&[10, 20]).rtt_median(), Some(Duration::from_millis(15)));
+  /// assert_eq!(timed(&[9, 1, 5]).rtt_median(), Some(Duration::from_millis(5)));
+  /// assert_eq!(timed(&[40, 10, 30, 20]).rtt_median(), Some(Duration::from_millis(25)));
+  /// ```
   pub fn rtt_median(&self) -> Option<Duration> {
-    match self.timings.len() {
-      | 0 => None,
-      | len => {
-        let mut timings = self.timings.clone();
-        timings.sort();
+    median(&self.timings)
+  }
 
-        let middle = len / 2;
+  /// Gets the median RTT, but only if it's based on at least `min_samples` successful pings. A
+  /// single lucky response shouldn't pass for a typical RTT.
+  pub fn rtt_median_reliable(&self, min_samples: usize) -> Option<Duration> {
+    self
+      .rtt_median()
+      .filter(|_| self.timings.len() >= min_samples)
+  }
+}
 
-        if len % 2 == 0 {
-          Some((timings[middle - 1] + timings[middle]) / 2)
-        } else {
-          Some(timings[middle])
-        }
-      },
+impl Display for RelayTimed {
+  /// Formats the relay followed by its median RTT in milliseconds, or a note that it never
+  /// responded.
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.rtt_median() {
+      | Some(rtt) => write!(f, "{}: {:.2} ms", self.relay, rtt.as_secs_f64() * 1_000.0),
+      | None => write!(f, "{}: no response", self.relay),
     }
   }
 }
 
+/// Finds the median of the given timings.
+fn median(timings: &[Duration]) -> Option<Duration> {
+  match timings.len() {
+    | 0 => None,
+    | len => {
+      let mut timings = timings.to_vec();
+      timings.sort();
+
+      let middle = len / 2;
+
+      if len % 2 == 0 {
+        Some((timings[middle - 1] + timings[middle]) / 2)
+      } else {
+        Some(timings[middle])
+      }
+    },
+  }
+}
+
 #[derive(Debug)]
 pub struct RelayPinger {
   /// Relay to ping.
@@ -119,8 +724,23 @@ impl RelayPinger {
 
   /// Execute the pinger.
   pub async fn execute(self) -> RelayTimed {
-    // I'm not entirely sure about hardcoding port 80, but it seems to be open on servers I checked.
-    let ping_addr = format!("{}:80", self.relay.ip);
+    let primary = Endpoint {
+      addr: host_port(&self.relay.ip, self.config.port),
+      family: AddressFamily::of(&self.relay.ip),
+    };
+
+    // The IPv6 address to race against, if enabled and the relay has one.
+    let secondary = self
+      .relay
+      .ipv6
+      .as_ref()
+      .filter(|_| self.config.happy_eyeballs)
+      .map(|ip| {
+        Endpoint {
+          addr: host_port(ip, self.config.port),
+          family: Some(AddressFamily::IPv6),
+        }
+      });
 
     // Set up the interval...
     let mut interval = time::interval(self.config.interval);
@@ -130,69 +750,792 @@ impl RelayPinger {
     interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
     let mut timings = Vec::new();
+    let mut families = Vec::new();
+    let mut samples = Vec::new();
+
+    // With a margin, keep pinging past the count until the mean is known precisely enough.
+    let max_count = match self.config.margin {
+      | Some(..) => self.config.max_count.max(self.config.count),
+      | None => self.config.count,
+    };
+
+    // Put the relay under some pressure while measuring. The load is stopped once the guards are
+    // dropped. Like other extra probes, it can't go through the SOCKS5 proxy.
+    let load = if self.config.socks5.is_none() {
+      (0..self.config.concurrent_load)
+        .map(|_| {
+          AbortOnDrop(tokio::spawn(generate_load(
+            primary.addr.clone(),
+            self.timeout(),
+          )))
+        })
+        .collect()
+    } else {
+      Vec::new()
+    };
+
+    // Running mean and sum of squared deviations (in seconds), updated using Welford's algorithm.
+    let mut mean = 0.0;
+    let mut m2 = 0.0;
+    let mut attempts = 0;
 
-    for _ in 1..=self.config.count {
+    while attempts < max_count {
+      if attempts >= self.config.count {
+        let n = timings.len() as f64;
+
+        if let Some(margin) = self.config.margin.filter(|_| n >= 2.0) {
+          let std_error = (m2 / (n - 1.0) / n).sqrt();
+
+          if std_error <= margin.as_secs_f64() {
+            break;
+          }
+        }
+      }
+
+      attempts += 1;
       interval.tick().await;
 
+      let started = SystemTime::now();
+
+      if let Some((elapsed, family)) = self.probe(&primary, secondary.as_ref()).await {
+        timings.push(elapsed);
+        families.extend(family);
+
+        let delta = elapsed.as_secs_f64() - mean;
+        mean += delta / timings.len() as f64;
+        m2 += delta * (elapsed.as_secs_f64() - mean);
+
+        if self.config.record_timestamps {
+          samples.push((started, elapsed));
+        }
+      }
+    }
+
+    drop(load);
+
+    // UDP can't go through the SOCKS5 proxy, so the probe is skipped in that case.
+    let udp = if self.config.udp
+      && self.config.socks5.is_none()
+      && self.relay.protocol == Some(Protocol::WireGuard)
+    {
+      Some(self.probe_udp().await)
+    } else {
+      None
+    };
+
+    // Binding to specific source ports isn't possible through the SOCKS5 proxy either.
+    let variants = if self.config.probe_variants > 0 && self.config.socks5.is_none() {
+      self.probe_variants(&primary.addr).await
+    } else {
+      Vec::new()
+    };
+
+    RelayTimed::new(self.relay, timings)
+      .with_attempts(attempts)
+      .with_families(families)
+      .with_udp(udp)
+      .with_variants(variants)
+      .with_samples(samples)
+      .with_probe(self.config.probe)
+  }
+
+  /// Pings the relay from several fixed source ports. On networks using ECMP, different source
+  /// ports can be routed along different paths, so comparing their timings reveals path-dependent
+  /// latency. Returns the median RTT for each port that got any response.
+  async fn probe_variants(&self, addr: &str) -> Vec<Duration> {
+    let Some(addr) = net::lookup_host(addr)
+      .await
+      .ok()
+      .and_then(|mut addrs| addrs.next())
+    else {
+      return Vec::new();
+    };
+
+    let mut medians = Vec::new();
+
+    for _ in 0..self.config.probe_variants {
+      // Port 0 lets the OS pick the port on the first connect, which is then reused.
+      let mut port = 0;
+      let mut timings = Vec::new();
+
+      for _ in 0..self.config.count {
+        let Ok(socket) = Self::variant_socket(addr, port) else {
+          break;
+        };
+
+        port = socket.local_addr().map_or(0, |local| local.port());
+
+        let start = Instant::now();
+
+        if let Ok(Ok(stream)) = time::timeout(self.timeout(), socket.connect(addr)).await {
+          timings.push(start.elapsed());
+
+          // Reset the connection instead of closing it gracefully, so the same 4-tuple doesn't get
+          // stuck in TIME_WAIT and can be reused right away. A zero linger timeout never blocks on
+          // drop, which is what tokio deprecated its own setter over.
+          let _ = SockRef::from(&stream).set_linger(Some(Duration::ZERO));
+        }
+      }
+
+      if let Some(median) = median(&timings) {
+        medians.push(median);
+      }
+    }
+
+    medians
+  }
+
+  /// Creates a socket bound to the given source port, or to any port if it's 0.
+  fn variant_socket(addr: SocketAddr, port: u16) -> io::Result<TcpSocket> {
+    let (socket, local) = match addr {
+      | SocketAddr::V4(..) => (TcpSocket::new_v4()?, SocketAddr::from(([0; 4], port))),
+      | SocketAddr::V6(..) => (TcpSocket::new_v6()?, SocketAddr::from(([0; 16], port))),
+    };
+
+    socket.set_reuseaddr(true)?;
+    socket.bind(local)?;
+
+    Ok(socket)
+  }
+
+  /// Sends a message shaped like a WireGuard handshake initiation to the WireGuard port and waits
+  /// for anything to come back. Connecting the socket makes ICMP errors surface on receive.
+  async fn probe_udp(&self) -> UdpStatus {
+    let addr = host_port(&self.relay.ip, WIREGUARD_PORT);
+
+    let bind = match AddressFamily::of(&self.relay.ip) {
+      | Some(AddressFamily::IPv6) => "[::]:0",
+      | _ => "0.0.0.0:0",
+    };
+
+    let socket = async {
+      let socket = UdpSocket::bind(bind).await?;
+      socket.connect(&addr).await?;
+
+      // Message type 1 (handshake initiation) followed by three reserved zero bytes and zeroed
+      // fields, which the relay can't authenticate and thus never answers.
+      let mut message = [0u8; HANDSHAKE_INITIATION_LEN];
+      message[0] = 1;
+
+      socket.send(&message).await?;
+
+      io::Result::Ok(socket)
+    };
+
+    let Ok(socket) = socket.await else {
+      return UdpStatus::Unreachable;
+    };
+
+    let mut buffer = [0u8; HANDSHAKE_INITIATION_LEN];
+
+    match time::timeout(self.timeout(), socket.recv(&mut buffer)).await {
+      | Ok(Ok(..)) => UdpStatus::Replied,
+      | Ok(Err(..)) => UdpStatus::Unreachable,
+      // An ICMP error doesn't necessarily wake up the pending receive, so check for it explicitly.
+      | Err(..) => {
+        match socket.take_error() {
+          | Ok(None) => UdpStatus::Silent,
+          | _ => UdpStatus::Unreachable,
+        }
+      },
+    }
+  }
+
+  /// Performs a single ping, retrying the connect if configured. The timeout window is split evenly
+  /// between attempts, so a dropped SYN doesn't eat the whole window. Returns the timing of the
+  /// successful attempt, if any, along with the address family used.
+  async fn probe(
+    &self,
+    primary: &Endpoint,
+    secondary: Option<&Endpoint>,
+  ) -> Option<(Duration, Option<AddressFamily>)> {
+    let timeout = self.timeout();
+    let attempts = self.config.connect_retries + 1;
+    let attempt_timeout = timeout / attempts as u32;
+    let deadline = Instant::now() + timeout;
+
+    for _ in 0..attempts {
       let start = Instant::now();
-      let stream = TcpStream::connect(&ping_addr);
+      let stream = self.connect_racing(primary, secondary);
 
-      match time::timeout(self.config.timeout, stream).await {
-        | Ok(Ok(..)) => {
+      match time::timeout_at(deadline.min(start + attempt_timeout), stream).await {
+        | Ok(Ok(family)) => {
           let end = Instant::now();
           let elapsed = end.duration_since(start);
 
-          timings.push(elapsed);
+          return Some((elapsed, family));
         },
         | Ok(Err(..)) => continue,
         | Err(..) => continue,
       }
     }
 
-    RelayTimed::new(self.relay, timings)
+    None
+  }
+
+  /// Returns the timeout for this relay, extended proportionally to its distance, if known. Like
+  /// the base timeout, it never exceeds the interval when there's more than one ping, so pings
+  /// don't overlap.
+  fn timeout(&self) -> Duration {
+    let distance = self.relay.distance.unwrap_or_default();
+    let timeout = self.config.timeout + self.config.timeout_scale.mul_f64(distance / 1_000.0);
+
+    if self.config.count > 1 {
+      timeout.min(self.config.interval.max(self.config.timeout))
+    } else {
+      timeout
+    }
+  }
+
+  /// Connects to the primary endpoint. If there's a secondary one, both are connected to
+  /// concurrently and the first to succeed wins. Returns the address family of the winner.
+  async fn connect_racing(
+    &self,
+    primary: &Endpoint,
+    secondary: Option<&Endpoint>,
+  ) -> io::Result<Option<AddressFamily>> {
+    let Some(secondary) = secondary else {
+      return self.connect(&primary.addr).await.map(|_| primary.family);
+    };
+
+    let primary_connect = self.connect(&primary.addr);
+    let secondary_connect = self.connect(&secondary.addr);
+
+    tokio::pin!(primary_connect, secondary_connect);
+
+    tokio::select! {
+      result = &mut primary_connect => match result {
+        | Ok(..) => Ok(primary.family),
+        | Err(..) => secondary_connect.await.map(|_| secondary.family),
+      },
+      result = &mut secondary_connect => match result {
+        | Ok(..) => Ok(secondary.family),
+        | Err(..) => primary_connect.await.map(|_| primary.family),
+      },
+    }
+  }
+
+  /// Connects to the given address, either directly or through the SOCKS5 proxy, if configured.
+  /// In the roundtrip mode, also waits for a reply to a small request.
+  async fn connect(&self, ping_addr: &str) -> io::Result<()> {
+    match &self.config.socks5 {
+      | Some(proxy) => {
+        let stream = Socks5Stream::connect(proxy.as_str(), ping_addr)
+          .await
+          .map_err(io::Error::other)?;
+
+        self.exchange(stream).await
+      },
+      | None => self.exchange(TcpStream::connect(ping_addr).await?).await,
+    }
+  }
+
+  /// Sends a small request over the established connection and waits for the first byte of the
+  /// reply in the roundtrip mode. The relay closing or resetting the connection counts as a reply
+  /// too, since it also takes a full round trip. Does nothing in the connect mode.
+  async fn exchange<S>(&self, mut stream: S) -> io::Result<()>
+  where
+    S: AsyncRead + AsyncWrite + Unpin,
+  {
+    if self.config.probe == ProbeMode::Connect {
+      return Ok(());
+    }
+
+    stream.write_all(ROUNDTRIP_REQUEST).await?;
+
+    let mut buffer = [0u8; 1];
+
+    match stream.read(&mut buffer).await {
+      | Ok(..) => Ok(()),
+      | Err(err) if err.kind() == io::ErrorKind::ConnectionReset => Ok(()),
+      | Err(err) => Err(err),
+    }
+  }
+}
+
+/// Keeps connecting to the given address and dropping the connections, pausing briefly between
+/// attempts so a relay refusing connections right away isn't hammered.
+async fn generate_load(addr: String, timeout: Duration) {
+  loop {
+    let _ = time::timeout(timeout, TcpStream::connect(&addr)).await;
+    time::sleep(LOAD_PAUSE).await;
+  }
+}
+
+/// Address to connect to when pinging.
+#[derive(Debug)]
+struct Endpoint {
+  /// Address in the `host:port` form.
+  addr: String,
+  /// Address family, if the host is an IP address.
+  family: Option<AddressFamily>,
+}
+
+/// Pings a single relay using the given config. This is a shortcut for when there's no need to ping
+/// relays concurrently.
+pub async fn ping_relay(relay: Relay, config: &RelayPingerConfig) -> RelayTimed {
+  RelayPinger::new(relay, Arc::new(config.clone()))
+    .execute()
+    .await
+}
+
+/// Results of pinging relays.
+#[derive(Debug)]
+pub struct PingResults {
+  /// Timed relays that matched all filters.
+  pub kept: Vec<RelayTimed>,
+  /// Number of timed relays that didn't match some filter.
+  pub filtered_out: usize,
+}
+
+impl PingResults {
+  /// Splits timed relays into the ones matching all filters and the rest.
+  pub fn filtered(
+    timings: Vec<RelayTimed>,
+    filters: &[Box<dyn Filter<Item = RelayTimed>>],
+  ) -> Self {
+    let mut results = Self {
+      kept: Vec::new(),
+      filtered_out: 0,
+    };
+
+    for timings in timings {
+      if filters.iter().all(|filter| filter.matches(&timings)) {
+        results.kept.push(timings);
+      } else {
+        results.filtered_out += 1;
+      }
+    }
+
+    results
+  }
+}
+
+/// Timed relays along with the context they were measured in.
+#[derive(Debug)]
+pub struct PingReport {
+  /// Timed relays.
+  timings: Vec<RelayTimed>,
+  /// Where relays came from.
+  source: RelaysSource,
+  /// Reference location distances were measured from, if any.
+  location: Option<Coord>,
+  /// Descriptions of the filters applied, in order.
+  filters: Vec<String>,
+  /// Number of timed relays that didn't match some filter.
+  filtered_out: usize,
+}
+
+impl PingReport {
+  pub fn new(timings: Vec<RelayTimed>, source: RelaysSource) -> Self {
+    Self {
+      timings,
+      source,
+      location: None,
+      filters: Vec::new(),
+      filtered_out: 0,
+    }
+  }
+
+  /// Sets the reference location.
+  pub fn with_location(mut self, location: Option<Coord>) -> Self {
+    self.location = location;
+    self
+  }
+
+  /// Sets the descriptions of the filters applied and how many timed relays they ruled out.
+  pub fn with_filters(mut self, filters: Vec<String>, filtered_out: usize) -> Self {
+    self.filters = filters;
+    self.filtered_out = filtered_out;
+    self
+  }
+
+  /// Returns where relays came from.
+  pub fn source(&self) -> &RelaysSource {
+    &self.source
+  }
+
+  /// Returns the reference location, if any.
+  pub fn location(&self) -> Option<Coord> {
+    self.location
+  }
+
+  /// Returns the descriptions of the filters applied, in order.
+  pub fn filters(&self) -> &[String] {
+    &self.filters
+  }
+
+  /// Returns the number of timed relays that didn't match some filter.
+  pub fn filtered_out(&self) -> usize {
+    self.filtered_out
+  }
+
+  /// Returns the number of timed relays.
+  pub fn len(&self) -> usize {
+    self.timings.len()
+  }
+
+  /// Checks if there are no timed relays.
+  pub fn is_empty(&self) -> bool {
+    self.timings.is_empty()
+  }
+
+  /// Iterates over timed relays.
+  pub fn iter(&self) -> slice::Iter<'_, RelayTimed> {
+    self.timings.iter()
+  }
+}
+
+impl IntoIterator for PingReport {
+  type IntoIter = vec::IntoIter<RelayTimed>;
+  type Item = RelayTimed;
+
+  fn into_iter(self) -> Self::IntoIter {
+    self.timings.into_iter()
+  }
+}
+
+impl<'a> IntoIterator for &'a PingReport {
+  type IntoIter = slice::Iter<'a, RelayTimed>;
+  type Item = &'a RelayTimed;
+
+  fn into_iter(self) -> Self::IntoIter {
+    self.iter()
+  }
+}
+
+/// Handle to track how many relays are done pinging.
+#[derive(Clone, Debug)]
+pub struct PingProgress {
+  /// Number of relays done pinging.
+  done: Arc<AtomicUsize>,
+  /// Total number of relays to ping.
+  total: usize,
+}
+
+impl PingProgress {
+  /// Returns the number of relays done pinging.
+  pub fn done(&self) -> usize {
+    self.done.load(Ordering::Relaxed)
+  }
+
+  /// Returns the total number of relays to ping.
+  pub fn total(&self) -> usize {
+    self.total
+  }
+
+  /// Estimates the remaining time given the time elapsed and the expected total duration. Once some
+  /// relays are done, the estimate is refined using the observed completion rate.
+  pub fn eta(&self, elapsed: Duration, expected: Duration) -> Duration {
+    let by_schedule = expected.saturating_sub(elapsed);
+
+    match self.done() {
+      | 0 => by_schedule,
+      | done => {
+        let left = self.total.saturating_sub(done) as f64;
+        let by_rate = elapsed.mul_f64(left / done as f64);
+
+        by_schedule.min(by_rate)
+      },
+    }
+  }
+}
+
+/// Aborts the task when dropped.
+#[derive(Debug)]
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+  fn drop(&mut self) {
+    self.0.abort();
   }
 }
 
 #[derive(Debug)]
 pub struct RelaysPinger {
-  /// Relay pinger tasks to await.
-  tasks: Vec<JoinHandle<RelayTimed>>,
+  /// Relay pinger tasks to await, each yielding the position of its relay along with the timings.
+  tasks: JoinSet<(usize, RelayTimed)>,
+  /// IPs of relays keyed by the IDs of their tasks, so failures can be attributed to relays.
+  ips: HashMap<task::Id, String>,
+  /// Progress of the pinger tasks.
+  progress: PingProgress,
   /// Filters to apply to timed relays after pinging.
   filters: Vec<Box<dyn Filter<Item = RelayTimed>>>,
 }
 
 impl RelaysPinger {
+  /// Spawns pinger tasks for the given relays, each using the config selected for it, e.g. by its
+  /// protocol.
   pub fn new(
     relays: Vec<Relay>,
-    config: Arc<RelayPingerConfig>,
+    configs: impl Into<RelayPingerConfigs>,
     filters: Vec<Box<dyn Filter<Item = RelayTimed>>>,
   ) -> Self {
-    let tasks = relays
-      .into_iter()
-      .map(|relay| {
-        let pinger = RelayPinger::new(relay, Arc::clone(&config));
+    let configs = configs.into();
 
-        tokio::spawn(pinger.execute())
-      })
-      .collect();
+    let progress = PingProgress {
+      done: Arc::new(AtomicUsize::new(0)),
+      total: relays.len(),
+    };
+
+    let mut tasks = JoinSet::new();
+    let mut ips = HashMap::new();
+
+    for (position, relay) in relays.into_iter().enumerate() {
+      let ip = relay.ip.clone();
+      let config = configs.for_relay(&relay);
+      let pinger = RelayPinger::new(relay, config);
+      let done = Arc::clone(&progress.done);
+
+      let handle = tasks.spawn(async move {
+        let timed = pinger.execute().await;
+
+        done.fetch_add(1, Ordering::Relaxed);
+        (position, timed)
+      });
+
+      ips.insert(handle.id(), ip);
+    }
+
+    Self {
+      tasks,
+      ips,
+      progress,
+      filters,
+    }
+  }
+
+  /// Returns a handle to track the progress of pinging.
+  pub fn progress(&self) -> PingProgress {
+    self.progress.clone()
+  }
 
-    Self { tasks, filters }
+  /// Execute all pings and wait for them to finish. Pings are collected as they finish, so slow
+  /// relays don't hold up the rest, but the results keep the order relays were given in.
+  pub async fn ping(self) -> Result<PingResults, RelaysPingerError> {
+    self.ping_until(future::pending()).await
   }
 
-  /// Execute all pings.
-  pub async fn ping(self) -> Result<Vec<RelayTimed>, RelaysPingerError> {
-    let mut results = Vec::new();
+  /// Same as [`RelaysPinger::ping`], but stops early once `interrupt` resolves. Unfinished pings
+  /// are aborted then and only the results of already finished ones are returned.
+  pub async fn ping_until<F>(self, interrupt: F) -> Result<PingResults, RelaysPingerError>
+  where
+    F: Future<Output = ()>,
+  {
+    tokio::pin!(interrupt);
 
-    for task in self.tasks {
-      let timings = task
-        .await
-        .map_err(|_| RelaysPingerError::PingerAwaitFailed)?;
+    let mut finished = Vec::new();
+    let mut tasks = self.tasks;
+    let mut interrupted = false;
 
-      if self.filters.iter().all(|filter| filter.matches(&timings)) {
-        results.push(timings);
+    loop {
+      let joined = tokio::select! {
+        joined = tasks.join_next() => joined,
+        _ = &mut interrupt, if !interrupted => {
+          // Aborting doesn't affect tasks that are already done, so their results are still
+          // collected below.
+          interrupted = true;
+          tasks.abort_all();
+          continue;
+        },
+      };
+
+      match joined {
+        | Some(Ok(timed)) => finished.push(timed),
+        // Tasks aborted on interrupt are expected to be cancelled.
+        | Some(Err(err)) if err.is_cancelled() && interrupted => {},
+        | Some(Err(err)) => {
+          let ip = self
+            .ips
+            .get(&err.id())
+            .cloned()
+            .ok_or(RelaysPingerError::PingerAwaitFailed)?;
+
+          return Err(if err.is_panic() {
+            RelaysPingerError::PingerPanicked { ip }
+          } else {
+            RelaysPingerError::PingerCancelled { ip }
+          });
+        },
+        | None => break,
       }
     }
 
-    Ok(results)
+    finished.sort_by_key(|(position, _)| *position);
+
+    let timings = finished
+      .into_iter()
+      .map(|(_, timed)| timed)
+      .collect::<Vec<_>>();
+
+    Ok(PingResults::filtered(timings, &self.filters))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Accepts connections on a random port and closes them after the given delay.
+  async fn listen(delay: Duration) -> u16 {
+    let listener = net::TcpListener::bind("0.0.0.0:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    tokio::spawn(async move {
+      while let Ok((stream, _)) = listener.accept().await {
+        tokio::spawn(async move {
+          time::sleep(delay).await;
+          drop(stream);
+        });
+      }
+    });
+
+    port
+  }
+
+  #[tokio::test]
+  async fn collects_every_relay_regardless_of_finish_order() {
+    let fast = listen(Duration::ZERO).await;
+    let slow = listen(Duration::from_millis(300)).await;
+
+    let config = |port| {
+      RelayPingerConfig::default()
+        .set_count(1)
+        .set_probe(ProbeMode::Roundtrip)
+        .set_timeout(Duration::from_secs(2))
+        .set_port(port)
+    };
+
+    // Slow relays go first, so fast ones finish before them.
+    let relays = (1..=6)
+      .map(|n| {
+        let protocol = if n <= 3 {
+          Protocol::OpenVPN
+        } else {
+          Protocol::WireGuard
+        };
+
+        Relay {
+          protocol: Some(protocol),
+          ..Relay::from_target(format!("127.0.0.{n}"))
+        }
+      })
+      .collect::<Vec<_>>();
+
+    let ips = relays
+      .iter()
+      .map(|relay| relay.ip.clone())
+      .collect::<Vec<_>>();
+
+    let configs =
+      RelayPingerConfigs::new(config(fast)).set_override(Protocol::OpenVPN, config(slow));
+
+    let results = RelaysPinger::new(relays, configs, Vec::new())
+      .ping()
+      .await
+      .unwrap();
+
+    let timed_ips = results
+      .kept
+      .iter()
+      .map(|timed| timed.relay().ip.clone())
+      .collect::<Vec<_>>();
+
+    // Every relay is collected, and in the order they were given despite finishing out of order.
+    assert_eq!(timed_ips, ips);
+    assert!(results.kept.iter().all(|timed| timed.timings().len() == 1));
+  }
+
+  #[test]
+  fn expects_variant_probes_to_take_time() {
+    let config = RelayPingerConfig::default()
+      .set_count(2)
+      .set_timeout(Duration::from_millis(500))
+      .set_interval(Duration::from_millis(1_000));
+
+    assert_eq!(config.expected_duration(), Duration::from_millis(1_500));
+
+    let config = config.set_probe_variants(3);
+
+    // Three variants with two connects each, after the pings.
+    assert_eq!(config.expected_duration(), Duration::from_millis(4_500));
+  }
+
+  #[test]
+  fn caps_scaled_timeout_at_interval() {
+    let config = RelayPingerConfig::default()
+      .set_timeout(Duration::from_millis(500))
+      .set_timeout_scale(Duration::from_millis(100))
+      .set_interval(Duration::from_millis(1_000));
+
+    let pinger = |distance, count| {
+      let relay = Relay {
+        distance: Some(distance),
+        ..Relay::from_target("127.0.0.1")
+      };
+
+      RelayPinger::new(relay, Arc::new(config.clone().set_count(count)))
+    };
+
+    assert_eq!(pinger(2_000.0, 8).timeout(), Duration::from_millis(700));
+    assert_eq!(pinger(10_000.0, 8).timeout(), Duration::from_millis(1_000));
+    // A single ping can't overlap with another one.
+    assert_eq!(pinger(10_000.0, 1).timeout(), Duration::from_millis(1_500));
+  }
+
+  #[test]
+  fn rejects_zero_count() {
+    let result = RelayPingerConfig::default().set_count(0).build();
+
+    assert!(matches!(result, Err(RelayPingerConfigError::ZeroCount)));
+  }
+
+  #[test]
+  fn rejects_zero_timeout() {
+    let result = RelayPingerConfig::default()
+      .set_timeout(Duration::ZERO)
+      .build();
+
+    assert!(matches!(result, Err(RelayPingerConfigError::ZeroTimeout)));
+  }
+
+  #[test]
+  fn rejects_timeout_exceeding_interval() {
+    let result = RelayPingerConfig::default()
+      .set_count(2)
+      .set_timeout(Duration::from_millis(1_500))
+      .set_interval(Duration::from_millis(1_000))
+      .build();
+
+    assert!(matches!(
+      result,
+      Err(RelayPingerConfigError::TimeoutExceedsInterval { timeout, interval })
+        if timeout == Duration::from_millis(1_500) && interval == Duration::from_millis(1_000)
+    ));
+  }
+
+  #[test]
+  fn allows_timeout_exceeding_interval_for_single_ping() {
+    let result = RelayPingerConfig::default()
+      .set_count(1)
+      .set_timeout(Duration::from_millis(1_500))
+      .set_interval(Duration::from_millis(1_000))
+      .build();
+
+    assert!(result.is_ok());
+  }
+
+  #[test]
+  fn rejects_invalid_protocol_override() {
+    let configs = RelayPingerConfigs::new(RelayPingerConfig::default()).set_override(
+      Protocol::WireGuard,
+      RelayPingerConfig::default().set_count(0),
+    );
+
+    assert!(RelayPingerConfig::default().validate().is_ok());
+    assert!(matches!(
+      configs.validate(),
+      Err(RelayPingerConfigError::ZeroCount)
+    ));
   }
 }
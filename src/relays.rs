@@ -1,54 +1,65 @@
+use std::collections::{BTreeSet, HashMap};
 use std::env::consts;
 use std::fmt::{self, Debug, Display};
 use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
+use serde::de::IgnoredAny;
+use serde::Deserialize;
 use serde_json::Value;
 use thiserror::Error;
+use tokio::net;
 
-use crate::coord::Coord;
+use crate::coord::{Coord, DistanceModel};
 use crate::filters::Filter;
 
-/// Macro helper to simplify accessing JSON fields and casting them.
-macro_rules! get {
-  ($data:expr, $field:expr, $method:ident) => {
-    $data[$field]
-      .$method()
-      .ok_or_else(|| RelaysError::ParseFieldFailed(stringify!($field).into()))?
-  };
-}
-
-/// Macro to simplify accessing values in a JSON object (map).
-macro_rules! value {
-  ($data:expr, $field:expr) => {
-    $data
-      .get($field)
-      .ok_or_else(|| RelaysError::ParseFieldFailed($field.into()))?
-  };
-}
-
 #[derive(Debug, Error)]
 pub enum RelaysError {
   #[error("Failed to read the relay file: {path}")]
-  ReadFileFailed {
-    path: PathBuf,
-    source: std::io::Error,
-  },
+  ReadFileFailed { path: PathBuf, source: io::Error },
+
+  #[error(
+    "Permission denied reading the relay file: {path}. Either run with sudo or copy the file \
+     somewhere readable and pass it via --relays-file"
+  )]
+  ReadFilePermissionDenied { path: PathBuf },
+
+  #[error(
+    "No relay file found at {0} and falling back to the Mullvad API is disabled. Pass the file \
+     via --relays-file"
+  )]
+  RemoteFallbackDisabled(String),
 
-  #[error("Failed to parse the relay file")]
+  #[error("Failed to parse the relay file: {0}")]
   ParseFileFailed(serde_json::Error),
 
-  #[error("Failed to parse the field {0}: it's either missing or malformed")]
-  ParseFieldFailed(String),
+  #[error(
+    "The relay file at {path} is empty or truncated, e.g. by an interrupted update. Run `mullvad \
+     relay update` to fetch it again"
+  )]
+  RelayFileTruncated { path: PathBuf },
+
+  #[error("Failed to read the Mullvad settings file: {path}")]
+  ReadSettingsFailed { path: PathBuf, source: io::Error },
+
+  #[error("Failed to parse the Mullvad settings file: {0}")]
+  ParseSettingsFailed(serde_json::Error),
 
   #[error("Could not load relays from the Mullvad API")]
   LoadRelaysFailed(reqwest::Error),
 
-  #[error("Failed to parse the response")]
-  ParseResponseFailed(reqwest::Error),
+  #[error("Could not load relays from {url}")]
+  LoadUrlFailed { url: String, source: reqwest::Error },
+
+  #[error("Failed to parse the response: {0}")]
+  ParseResponseFailed(serde_json::Error),
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum Protocol {
   OpenVPN,
   WireGuard,
@@ -63,22 +74,479 @@ impl Display for Protocol {
   }
 }
 
+/// Optional capabilities advertised by relays.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Feature {
+  /// Defense against AI-guided Traffic Analysis.
+  Daita,
+}
+
+impl Display for Feature {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      | Feature::Daita => write!(f, "DAITA"),
+    }
+  }
+}
+
+/// Roles relays play in the Mullvad network.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum RelayRole {
+  /// Regular VPN server, reachable over OpenVPN or WireGuard.
+  #[default]
+  Standard,
+  /// Bridge used to reach OpenVPN servers where VPN traffic is blocked.
+  Bridge,
+}
+
+impl Display for RelayRole {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      | RelayRole::Standard => write!(f, "standard"),
+      | RelayRole::Bridge => write!(f, "bridge"),
+    }
+  }
+}
+
 #[derive(Clone, Debug)]
 pub struct Relay {
   pub ip: String,
+  pub hostname: Option<String>,
+  pub ipv6: Option<String>,
   pub city: String,
   pub country: String,
-  pub coord: Coord,
-  pub protocol: Protocol,
+  /// Two-letter country code, e.g. `se`.
+  pub country_code: Option<String>,
+  pub provider: Option<String>,
+  pub coord: Option<Coord>,
+  pub protocol: Option<Protocol>,
+  pub role: RelayRole,
+  pub features: Vec<Feature>,
   pub is_active: bool,
   pub is_mullvad_owned: bool,
-  pub distance: f64,
+  pub distance: Option<f64>,
+  /// Hostname of the user-supplied target this relay was resolved from, if given by name.
+  pub target: Option<String>,
+}
+
+impl Relay {
+  /// Constructs a synthetic relay from a user-supplied IP or hostname. Such relays carry no geo or
+  /// protocol data, since they don't come from the relay list.
+  pub fn from_target<S>(target: S) -> Self
+  where
+    S: Into<String>,
+  {
+    Self {
+      ip: target.into(),
+      hostname: None,
+      ipv6: None,
+      city: String::new(),
+      country: String::new(),
+      country_code: None,
+      provider: None,
+      coord: None,
+      protocol: None,
+      role: RelayRole::Standard,
+      features: Vec::new(),
+      is_active: true,
+      is_mullvad_owned: false,
+      distance: None,
+      target: None,
+    }
+  }
+
+  /// Constructs a synthetic relay from a user-supplied IP or hostname, resolving the latter once,
+  /// so that DNS lookups don't end up in the timings. IPv4 addresses are preferred, if any.
+  ///
+  /// ```no_run
+  /// # async fn resolve() -> std::io::Result<()> {
+  /// use pingmole::Relay;
+  ///
+  /// let relay = Relay::resolve("am.i.mullvad.net").await?;
+  /// assert_eq!(relay.target.as_deref(), Some("am.i.mullvad.net"));
+  /// # Ok(())
+  /// # }
+  /// ```
+  pub async fn resolve<S>(target: S) -> io::Result<Self>
+  where
+    S: Into<String>,
+  {
+    let target = target.into();
+
+    if target.parse::<IpAddr>().is_ok() {
+      return Ok(Self::from_target(target));
+    }
+
+    // The port doesn't matter here, only addresses are of interest.
+    let addrs = net::lookup_host((target.as_str(), 0))
+      .await?
+      .collect::<Vec<_>>();
+
+    let addr = addrs
+      .iter()
+      .find(|addr| addr.is_ipv4())
+      .or_else(|| addrs.first())
+      .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses found"))?;
+
+    Ok(Self {
+      target: Some(target),
+      ..Self::from_target(addr.ip().to_string())
+    })
+  }
+
+  /// Recomputes the great-circle distance (in kilometers) from the given location, e.g. after the
+  /// reference point moved. Relays without coordinates keep an unknown distance. Apply a
+  /// [`DistanceModel`] to the result if the distance should be padded.
+  ///
+  /// ```
+  /// use pingmole::{Coord, Relay};
+  ///
+  /// let mut relay = Relay::from_target("127.0.0.1");
+  ///
+  /// relay.recompute_distance(&Coord::new(59.3289, 18.0649));
+  /// assert_eq!(relay.distance, None);
+  ///
+  /// relay.coord = Some(Coord::new(59.3289, 18.0649));
+  /// relay.recompute_distance(&Coord::new(59.3289, 18.0649));
+  /// assert_eq!(relay.distance, Some(0.0));
+  /// ```
+  pub fn recompute_distance(&mut self, from: &Coord) {
+    self.distance = self.coord.map(|coord| from.distance_to(&coord));
+  }
+}
+
+impl Display for Relay {
+  /// Formats the relay as `<ip> <protocol> <city>, <country> (~<distance> km)`, leaving out the
+  /// parts that aren't known, e.g. for user-supplied targets. Targets given by name are followed by
+  /// it, e.g. `185.65.135.67 (am.i.mullvad.net)`.
+  ///
+  /// ```
+  /// use pingmole::{Coord, Protocol, Relay};
+  ///
+  /// let mut relay = Relay::from_target("185.65.135.67");
+  /// assert_eq!(relay.to_string(), "185.65.135.67");
+  ///
+  /// relay.protocol = Some(Protocol::WireGuard);
+  /// relay.city = "Stockholm".to_string();
+  /// relay.country = "Sweden".to_string();
+  /// relay.distance = Some(36.6);
+  /// assert_eq!(relay.to_string(), "185.65.135.67 WireGuard Stockholm, Sweden (~37 km)");
+  /// ```
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.ip)?;
+
+    match (self.protocol, self.role) {
+      | (Some(protocol), _) => write!(f, " {protocol}")?,
+      | (None, RelayRole::Bridge) => write!(f, " {}", self.role)?,
+      | (None, RelayRole::Standard) => {},
+    }
+
+    if !self.city.is_empty() {
+      write!(f, " {}, {}", self.city, self.country)?;
+    }
+
+    if let Some(target) = &self.target {
+      write!(f, " ({target})")?;
+    }
+
+    if let Some(distance) = self.distance {
+      write!(f, " (~{} km)", distance.round())?;
+    }
+
+    Ok(())
+  }
+}
+
+/// Reads targets from a file, one IP or hostname per line. Empty lines and lines starting with `#`
+/// are skipped.
+pub fn read_targets(path: &Path) -> Result<Vec<String>, RelaysError> {
+  let data = fs::read_to_string(path).map_err(|source| {
+    RelaysError::ReadFileFailed {
+      path: path.to_owned(),
+      source,
+    }
+  })?;
+
+  let targets = data
+    .lines()
+    .map(str::trim)
+    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    .map(String::from)
+    .collect();
+
+  Ok(targets)
+}
+
+/// Shape of the local relay file. Only the fields we need are listed, the rest are ignored.
+#[derive(Debug, Deserialize)]
+struct RelayFile {
+  countries: Vec<RawCountry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawCountry {
+  name: String,
+  code: Option<String>,
+  cities: Vec<RawCity>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawCity {
+  name: String,
+  latitude: f64,
+  longitude: f64,
+  relays: Vec<Lenient<RawRelay>>,
+}
+
+/// Shape of the [Mullvad API][api] response. Only the fields we need are listed.
+///
+/// [api]: https://api.mullvad.net/app/documentation/#/paths/~1v1~1relays/get
+#[derive(Debug, Deserialize)]
+struct RelayList {
+  /// Locations keyed by their codes, e.g. `se-sto`.
+  locations: HashMap<String, RawLocation>,
+  openvpn: RawRelays,
+  wireguard: RawRelays,
+  #[serde(default)]
+  bridge: RawRelays,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawLocation {
+  country: String,
+  city: String,
+  latitude: f64,
+  longitude: f64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct RawRelays {
+  relays: Vec<Lenient<RawRelay>>,
+}
+
+/// Entry that doesn't fail the whole list if it's malformed, so a single bad relay is skipped
+/// instead of aborting the load.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Lenient<T> {
+  Parsed(T),
+  Malformed(IgnoredAny),
+}
+
+/// Shape of a single relay, shared by the local file and the API. Fields specific to either of
+/// them are optional.
+#[derive(Debug, Deserialize)]
+struct RawRelay {
+  hostname: Option<String>,
+  ipv4_addr_in: String,
+  ipv6_addr_in: Option<String>,
+  provider: Option<String>,
+  active: bool,
+  owned: bool,
+  /// Location code, e.g. `se-sto`, in the API. Newer relay files have an object with the relay's
+  /// own coordinates instead.
+  #[serde(default)]
+  location: Value,
+  /// Coordinates of the relay itself, if known. Relays in the same city may be in different
+  /// datacenters, so these are more precise than the city's.
+  latitude: Option<f64>,
+  longitude: Option<f64>,
+  /// Protocol-specific data. Only present in the local file.
+  #[serde(default)]
+  endpoint_data: Value,
+  #[serde(default)]
+  daita: Value,
+  #[serde(default)]
+  features: Value,
+}
+
+impl RawRelay {
+  /// Returns the location code, e.g. `se-sto`.
+  fn location_code(&self) -> Option<&str> {
+    self.location.as_str()
+  }
+
+  /// Returns the relay's own coordinates, either given directly or in its location object.
+  fn coord(&self) -> Option<Coord> {
+    let latitude = self
+      .latitude
+      .or_else(|| self.location["latitude"].as_f64())?;
+    let longitude = self
+      .longitude
+      .or_else(|| self.location["longitude"].as_f64())?;
+
+    Some(Coord::new(latitude, longitude))
+  }
+}
+
+/// Returns the path to the Mullvad settings file.
+pub fn resolve_settings_path() -> Option<PathBuf> {
+  let path = match consts::OS {
+    | "linux" | "macos" => Some("/etc/mullvad-vpn/settings.json"),
+    | "windows" => Some("C:/ProgramData/Mullvad VPN/settings.json"),
+    | _ => None,
+  };
+
+  path.map(PathBuf::from)
+}
+
+/// Reads the tunnel protocol from the Mullvad settings file. The protocol is stored in one of the
+/// following forms, depending on the app version:
+///
+/// ```json
+/// "tunnel_protocol": "wireguard",
+/// "tunnel_protocol": { "only": "wireguard" },
+/// ```
+///
+/// Returns `None` if any protocol is allowed.
+pub fn read_settings_protocol(path: &Path) -> Result<Option<Protocol>, RelaysError> {
+  let data = fs::read_to_string(path).map_err(|source| {
+    RelaysError::ReadSettingsFailed {
+      path: path.to_owned(),
+      source,
+    }
+  })?;
+
+  let data = serde_json::from_str::<Value>(&data).map_err(RelaysError::ParseSettingsFailed)?;
+  let protocol = &data["relay_settings"]["normal"]["tunnel_protocol"];
+
+  let protocol = match protocol {
+    | Value::Object(o) => o.get("only").and_then(Value::as_str),
+    | protocol => protocol.as_str(),
+  };
+
+  Ok(match protocol {
+    | Some("wireguard") => Some(Protocol::WireGuard),
+    | Some("openvpn") => Some(Protocol::OpenVPN),
+    | _ => None,
+  })
 }
 
 #[derive(Debug)]
 pub struct RelaysLoaderConfig {
   /// Current user location.
   pub location: Coord,
+  /// Whether to keep inactive relays.
+  pub include_inactive: bool,
+  /// How distances to relays are estimated.
+  pub distance_model: DistanceModel,
+  /// Relay file to load instead of the one cached by the Mullvad app. Unlike the cached one, it
+  /// must exist.
+  pub relays_file: Option<PathBuf>,
+  /// Whether to load relays from the Mullvad API if the relay file can't be found.
+  pub remote_fallback: bool,
+  /// URL to load a relay file from instead of the local one, e.g. a self-hosted mirror.
+  pub relays_url: Option<String>,
+}
+
+/// Where relays came from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RelaysSource {
+  /// The relay file cached by the Mullvad app.
+  LocalFile(PathBuf),
+  /// The Mullvad API.
+  Api,
+  /// A relay file served at the given URL.
+  Url(String),
+  /// IPs or hostnames supplied by the user.
+  Targets,
+}
+
+impl Display for RelaysSource {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      | RelaysSource::LocalFile(path) => write!(f, "{}", path.display()),
+      | RelaysSource::Api => write!(f, "Mullvad API"),
+      | RelaysSource::Url(url) => write!(f, "{url}"),
+      | RelaysSource::Targets => write!(f, "targets"),
+    }
+  }
+}
+
+/// Counts of relays at each loading step, useful to explain empty results.
+#[derive(Debug)]
+pub struct RelaysLoaderStats {
+  /// Where relays were loaded from.
+  pub source: RelaysSource,
+  /// Number of relays loaded before filtering.
+  pub loaded: usize,
+  /// Number of malformed relays skipped while loading.
+  pub malformed: usize,
+  /// Number of relays left after each filter, in the order filters were applied.
+  pub survived: Vec<(String, usize)>,
+}
+
+impl Display for RelaysLoaderStats {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Loaded {} relays from {}", self.loaded, self.source)?;
+
+    if self.malformed > 0 {
+      write!(f, " (skipped {} malformed)", self.malformed)?;
+    }
+
+    for (filter, count) in &self.survived {
+      write!(f, "\n  {count} left after filtering by {filter}")?;
+    }
+
+    Ok(())
+  }
+}
+
+/// Differences between the relays in the local file and the ones currently listed by the API.
+#[derive(Debug, Default)]
+pub struct StaleRelays {
+  /// IPs of relays in the local file the API no longer lists.
+  pub removed: BTreeSet<String>,
+  /// IPs of relays listed by the API that are missing from the local file.
+  pub added: BTreeSet<String>,
+}
+
+impl StaleRelays {
+  /// Checks if the local file matches the API.
+  pub fn is_empty(&self) -> bool {
+    self.removed.is_empty() && self.added.is_empty()
+  }
+
+  /// Checks if the relay is no longer listed by the API.
+  pub fn is_removed(&self, relay: &Relay) -> bool {
+    self.removed.contains(&relay.ip)
+  }
+}
+
+impl Display for StaleRelays {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "The relay file seems to be out of date: {} of its relays are no longer listed by the API \
+       and {} listed ones are missing from it",
+      self.removed.len(),
+      self.added.len()
+    )
+  }
+}
+
+/// Handle to track how much of the relay list has been downloaded from the API.
+#[derive(Clone, Debug, Default)]
+pub struct LoadProgress {
+  /// Number of bytes downloaded so far.
+  downloaded: Arc<AtomicU64>,
+  /// Expected number of bytes, or 0 if unknown.
+  total: Arc<AtomicU64>,
+}
+
+impl LoadProgress {
+  /// Returns the number of bytes downloaded so far.
+  pub fn downloaded(&self) -> u64 {
+    self.downloaded.load(Ordering::Relaxed)
+  }
+
+  /// Returns the expected number of bytes, if the API reported it.
+  pub fn total(&self) -> Option<u64> {
+    match self.total.load(Ordering::Relaxed) {
+      | 0 => None,
+      | total => Some(total),
+    }
+  }
 }
 
 #[derive(Debug)]
@@ -89,22 +557,44 @@ pub struct RelaysLoader {
   config: RelaysLoaderConfig,
   /// Filters to apply to the loaded relays.
   filters: Vec<Box<dyn Filter<Item = Relay>>>,
+  /// Progress of downloading relays from the API.
+  progress: LoadProgress,
 }
 
 impl RelaysLoader {
   pub fn new(config: RelaysLoaderConfig, filters: Vec<Box<dyn Filter<Item = Relay>>>) -> Self {
-    let path = Self::resolve_path();
+    let path = config.relays_file.clone().or_else(Self::resolve_path);
 
     Self {
       path,
       config,
       filters,
+      progress: LoadProgress::default(),
     }
   }
 
-  /// Returns the path to the relay file.
+  /// Returns a handle to track the progress of downloading relays from the API.
+  pub fn progress(&self) -> LoadProgress {
+    self.progress.clone()
+  }
+
+  /// Returns the path to the relay file: the first of the candidate paths that exists, or the
+  /// system-wide one if none do, so errors point to where the file is usually found.
   pub fn resolve_path() -> Option<PathBuf> {
-    let path = match consts::OS {
+    let candidates = Self::candidate_paths();
+
+    candidates
+      .iter()
+      .find(|path| path.try_exists().unwrap_or(false))
+      .or(candidates.first())
+      .cloned()
+  }
+
+  /// Returns the paths the relay file may be found at, in order of preference. The system-wide
+  /// cache of the Mullvad daemon comes first, followed by per-user cache directories, which are
+  /// used by sandboxed (e.g. Flatpak) and user-level installs.
+  pub fn candidate_paths() -> Vec<PathBuf> {
+    let system = match consts::OS {
       // NOTE: On Ubuntu and likely some other distros this is wrong.
       | "linux" => Some("/var/cache/mullvad-vpn/relays.json"),
       | "macos" => Some("/Library/Caches/mullvad-vpn/relays.json"),
@@ -112,7 +602,24 @@ impl RelaysLoader {
       | _ => None,
     };
 
-    path.map(PathBuf::from)
+    let mut paths = system.map(PathBuf::from).into_iter().collect::<Vec<_>>();
+
+    // This is `$XDG_CACHE_HOME` on Linux, `~/Library/Caches` on macOS and `%LOCALAPPDATA%` on
+    // Windows.
+    if let Some(cache) = dirs::cache_dir() {
+      match consts::OS {
+        | "windows" => paths.push(cache.join("Mullvad VPN").join("cache").join("relays.json")),
+        | _ => paths.push(cache.join("mullvad-vpn").join("relays.json")),
+      }
+    }
+
+    if consts::OS == "linux" {
+      if let Some(home) = dirs::home_dir() {
+        paths.push(home.join(".var/app/net.mullvad.MullvadVPN/cache/mullvad-vpn/relays.json"));
+      }
+    }
+
+    paths
   }
 
   /// Parses a protocol stored in the `endpoint_data` field of a relay, which can be either of the
@@ -128,132 +635,495 @@ impl RelaysLoader {
   /// }
   /// ```
   ///
-  /// We actually not interested in those with "bridge", so skip them with other ones.
-  pub fn resolve_protocol(relay: &Value) -> Option<Protocol> {
-    match &relay["endpoint_data"] {
+  /// Bridges don't have a protocol of their own, so they are told apart by the caller.
+  pub fn resolve_protocol(endpoint_data: &Value) -> Option<Protocol> {
+    match endpoint_data {
       | Value::String(ref s) => s.eq("openvpn").then_some(Protocol::OpenVPN),
       | Value::Object(o) => o.get("wireguard").map(|_| Protocol::WireGuard),
       | _ => None,
     }
   }
 
-  /// Loads the relays, either from local file or from the API.
-  pub async fn load(&self) -> anyhow::Result<Vec<Relay>> {
-    if matches!(&self.path, Some(path) if path.try_exists().unwrap_or(false)) {
-      self.load_local()
-    } else {
-      self.load_remote().await
+  /// Parses the features supported by a relay. Depending on the source and its version, the DAITA
+  /// flag can be found in any of the following places:
+  ///
+  /// ```json
+  /// "daita": true,
+  /// "features": { "daita": {} },
+  /// "endpoint_data": {
+  ///   "wireguard": {
+  ///     "daita": true
+  ///   }
+  /// }
+  /// ```
+  fn resolve_features(relay: &RawRelay) -> Vec<Feature> {
+    let flag = |value: &Value| {
+      match value {
+        | Value::Bool(b) => *b,
+        | Value::Object(..) => true,
+        | _ => false,
+      }
+    };
+
+    let daita = flag(&relay.daita)
+      || flag(&relay.features["daita"])
+      || flag(&relay.endpoint_data["wireguard"]["daita"]);
+
+    daita.then_some(Feature::Daita).into_iter().collect()
+  }
+
+  /// Loads the relays, either from local file or from the API, and filters them. The API is only
+  /// used if the file can't be found and falling back to it is allowed.
+  pub async fn load(&self) -> anyhow::Result<(Vec<Relay>, RelaysLoaderStats)> {
+    let (relays, source, malformed) = self.load_raw().await?;
+
+    Ok(self.filter(relays, source, malformed))
+  }
+
+  /// Same as [`RelaysLoader::load`], but skips the filters, so callers can apply their own
+  /// selection logic. Inactive relays are still skipped unless the config says otherwise, and
+  /// distances are computed.
+  ///
+  /// ```no_run
+  /// use pingmole::{Coord, DistanceModel, RelaysLoader, RelaysLoaderConfig};
+  ///
+  /// # async fn example() -> anyhow::Result<()> {
+  /// let config = RelaysLoaderConfig {
+  ///   location: Coord::new(59.3289, 18.0649),
+  ///   include_inactive: false,
+  ///   distance_model: DistanceModel::GreatCircle,
+  ///   relays_file: None,
+  ///   remote_fallback: true,
+  ///   relays_url: None,
+  /// };
+  ///
+  /// let (relays, _) = RelaysLoader::new(config, Vec::new()).load_unfiltered().await?;
+  /// let rented = relays.iter().filter(|relay| !relay.is_mullvad_owned).count();
+  /// # Ok(())
+  /// # }
+  /// ```
+  pub async fn load_unfiltered(&self) -> anyhow::Result<(Vec<Relay>, RelaysSource)> {
+    let (relays, source, _) = self.load_raw().await?;
+
+    Ok((relays, source))
+  }
+
+  /// Loads the relays without filtering them, returning where they came from and the number of
+  /// malformed ones skipped.
+  async fn load_raw(&self) -> anyhow::Result<(Vec<Relay>, RelaysSource, usize)> {
+    if let Some(url) = &self.config.relays_url {
+      let (relays, malformed) = self.load_url(url).await?;
+      return Ok((relays, RelaysSource::Url(url.clone()), malformed));
     }
+
+    let (source, (relays, malformed)) = match &self.path {
+      | Some(path) if self.config.relays_file.is_some() || path.try_exists().unwrap_or(false) => {
+        (RelaysSource::LocalFile(path.clone()), self.load_local()?)
+      },
+      | path if !self.config.remote_fallback => {
+        let path = path
+          .as_ref()
+          .map_or("an unknown location".to_string(), |path| {
+            path.display().to_string()
+          });
+
+        return Err(RelaysError::RemoteFallbackDisabled(path).into());
+      },
+      | _ => (RelaysSource::Api, self.load_remote().await?),
+    };
+
+    Ok((relays, source, malformed))
   }
 
-  /// Loads the relays from the local file.
-  fn load_local(&self) -> anyhow::Result<Vec<Relay>> {
-    let mut results = Vec::new();
+  /// Compares the relays in the local file with the ones currently listed by the API. Filters
+  /// aren't applied, so the whole file is compared.
+  pub async fn find_stale(&self) -> anyhow::Result<StaleRelays> {
+    let (local, _) = self.load_local()?;
+    let (remote, _) = self.load_remote().await?;
+
+    let local = local
+      .into_iter()
+      .map(|relay| relay.ip)
+      .collect::<BTreeSet<_>>();
+    let remote = remote
+      .into_iter()
+      .map(|relay| relay.ip)
+      .collect::<BTreeSet<_>>();
+
+    Ok(StaleRelays {
+      removed: local.difference(&remote).cloned().collect(),
+      added: remote.difference(&local).cloned().collect(),
+    })
+  }
+
+  /// Applies filters one by one, keeping track of how many relays are left after each of them.
+  fn filter(
+    &self,
+    mut relays: Vec<Relay>,
+    source: RelaysSource,
+    malformed: usize,
+  ) -> (Vec<Relay>, RelaysLoaderStats) {
+    let mut stats = RelaysLoaderStats {
+      source,
+      loaded: relays.len(),
+      malformed,
+      survived: Vec::new(),
+    };
 
+    for filter in &self.filters {
+      relays.retain(|relay| filter.matches(relay));
+      stats.survived.push((filter.to_string(), relays.len()));
+    }
+
+    (relays, stats)
+  }
+
+  /// Loads relays from the local file. See [`RelaysLoader::parse_relays`] for details.
+  fn load_local(&self) -> anyhow::Result<(Vec<Relay>, usize)> {
     let path = match &self.path {
       | Some(path) => path,
-      | None => return Ok(results),
+      | None => return Ok((Vec::new(), 0)),
     };
 
     // Read into a string.
     let data = fs::read_to_string(path).map_err(|source| {
-      RelaysError::ReadFileFailed {
-        path: path.to_owned(),
-        source,
+      // The cache directory is often owned by root, so spell out how to get around that.
+      if source.kind() == io::ErrorKind::PermissionDenied {
+        RelaysError::ReadFilePermissionDenied {
+          path: path.to_owned(),
+        }
+      } else {
+        RelaysError::ReadFileFailed {
+          path: path.to_owned(),
+          source,
+        }
       }
     })?;
 
-    // Parse the string as arbitrary JSON.
-    let data = serde_json::from_str::<Value>(&data).map_err(RelaysError::ParseFileFailed)?;
-
-    for country in get!(data, "countries", as_array) {
-      for city in get!(country, "cities", as_array) {
-        for relay in get!(city, "relays", as_array) {
-          // We only need relays that have either "openvpn" or "wireguard" protocols.
-          if let Some(protocol) = Self::resolve_protocol(relay) {
-            let coord = Coord::new(
-              get!(city, "latitude", as_f64),
-              get!(city, "longitude", as_f64),
-            );
-
-            let distance = self.config.location.distance_to(&coord);
-
-            let relay = Relay {
-              coord,
-              protocol,
-              distance,
-              ip: get!(relay, "ipv4_addr_in", as_str).to_string(),
-              city: get!(city, "name", as_str).to_string(),
-              country: get!(country, "name", as_str).to_string(),
-              is_active: get!(relay, "active", as_bool),
-              is_mullvad_owned: get!(relay, "owned", as_bool),
-            };
-
-            // There's no reason to filter inactive relays.
-            if relay.is_active && self.filters.iter().all(|filter| filter.matches(&relay)) {
-              results.push(relay);
-            }
+    // An empty or cut off file fails to parse at the very end, which deserves a better hint than a
+    // generic parse error.
+    let relays = Self::parse_relays(&data, &self.config).map_err(|error| {
+      match error {
+        | RelaysError::ParseFileFailed(source) if source.is_eof() => {
+          RelaysError::RelayFileTruncated {
+            path: path.to_owned(),
+          }
+        },
+        | error => error,
+      }
+    })?;
+
+    Ok(relays)
+  }
+
+  /// Parses relays from the contents of a relay file in the format cached by the Mullvad app,
+  /// skipping inactive ones unless asked otherwise. Returns the relays along with the number of
+  /// malformed ones skipped.
+  ///
+  /// Filters aren't applied here: they belong to the loader, and [`RelaysLoader::load`] applies
+  /// them to relays from any source and counts what each one dropped for the stats. That keeps the
+  /// parser usable on its own, e.g. by [`RelaysLoader::load_unfiltered`].
+  ///
+  /// ```
+  /// use pingmole::{Coord, DistanceModel, RelaysLoader, RelaysLoaderConfig};
+  ///
+  /// let data = r#"{
+  ///   "countries": [{
+  ///     "name": "Sweden",
+  ///     "cities": [{
+  ///       "name": "Stockholm",
+  ///       "latitude": 59.3289,
+  ///       "longitude": 18.0649,
+  ///       "relays": [{
+  ///         "hostname": "se-sto-wg-001",
+  ///         "ipv4_addr_in": "185.65.135.67",
+  ///         "active": true,
+  ///         "owned": true,
+  ///         "endpoint_data": { "wireguard": { "public_key": "..." } }
+  ///       }]
+  ///     }]
+  ///   }]
+  /// }"#;
+  ///
+  /// let config = RelaysLoaderConfig {
+  ///   location: Coord::new(59.3289, 18.0649),
+  ///   include_inactive: false,
+  ///   distance_model: DistanceModel::GreatCircle,
+  ///   relays_file: None,
+  ///   remote_fallback: false,
+  ///   relays_url: None,
+  /// };
+  ///
+  /// let (relays, _) = RelaysLoader::parse_relays(data, &config).unwrap();
+  /// ```
+  pub fn parse_relays(
+    data: &str,
+    config: &RelaysLoaderConfig,
+  ) -> Result<(Vec<Relay>, usize), RelaysError> {
+    // Parse the string straight into the shape of the file, ignoring fields we don't need.
+    let data = serde_json::from_str::<RelayFile>(data).map_err(RelaysError::ParseFileFailed)?;
+
+    Ok(Self::collect_relays(data, config))
+  }
+
+  /// Turns the parsed relay file into relays. See [`RelaysLoader::parse_relays`] for details.
+  fn collect_relays(data: RelayFile, config: &RelaysLoaderConfig) -> (Vec<Relay>, usize) {
+    let mut results = Vec::new();
+    let mut malformed = 0;
+
+    for country in data.countries {
+      for city in country.cities {
+        let city_coord = Coord::new(city.latitude, city.longitude);
+
+        for relay in city.relays {
+          let relay = match relay {
+            | Lenient::Parsed(relay) => relay,
+            | Lenient::Malformed(..) => {
+              malformed += 1;
+              continue;
+            },
+          };
+
+          // We only need bridges and relays that have either "openvpn" or "wireguard" protocols.
+          let (role, protocol) = match Self::resolve_protocol(&relay.endpoint_data) {
+            | Some(protocol) => (RelayRole::Standard, Some(protocol)),
+            | None if relay.endpoint_data == "bridge" => (RelayRole::Bridge, None),
+            | None => continue,
+          };
+
+          // Prefer the relay's own coordinates, falling back to the city's.
+          let coord = relay.coord().unwrap_or(city_coord);
+          let distance = config
+            .distance_model
+            .apply(config.location.distance_to(&coord));
+
+          let relay = Relay {
+            coord: Some(coord),
+            protocol,
+            role,
+            features: Self::resolve_features(&relay),
+            distance: Some(distance),
+            ip: relay.ipv4_addr_in,
+            hostname: relay.hostname,
+            ipv6: relay.ipv6_addr_in,
+            city: city.name.clone(),
+            country: country.name.clone(),
+            country_code: country.code.clone(),
+            provider: relay.provider,
+            is_active: relay.active,
+            is_mullvad_owned: relay.owned,
+            target: None,
+          };
+
+          // There's usually no reason to ping inactive relays.
+          if relay.is_active || config.include_inactive {
+            results.push(relay);
           }
         }
       }
     }
 
-    Ok(results)
+    (results, malformed)
   }
 
-  /// Gets the relays using the [Mullvad API][api].
+  /// Loads relays from a relay file served at the given URL. It has the same shape as the local
+  /// file, not the API response.
+  async fn load_url(&self, url: &str) -> anyhow::Result<(Vec<Relay>, usize)> {
+    let body = self.download(url).await.map_err(|source| {
+      RelaysError::LoadUrlFailed {
+        url: url.to_string(),
+        source,
+      }
+    })?;
+
+    let data = serde_json::from_slice::<RelayFile>(&body).map_err(RelaysError::ParseFileFailed)?;
+
+    Ok(Self::collect_relays(data, &self.config))
+  }
+
+  /// Downloads the body at the given URL. Relay lists are large, so it's done chunk by chunk to
+  /// report progress on slow links.
+  async fn download(&self, url: &str) -> Result<Vec<u8>, reqwest::Error> {
+    let mut response = reqwest::get(url).await?.error_for_status()?;
+    let mut body = Vec::new();
+
+    self
+      .progress
+      .total
+      .store(response.content_length().unwrap_or(0), Ordering::Relaxed);
+
+    while let Some(chunk) = response.chunk().await? {
+      body.extend_from_slice(&chunk);
+
+      self
+        .progress
+        .downloaded
+        .fetch_add(chunk.len() as u64, Ordering::Relaxed);
+    }
+
+    Ok(body)
+  }
+
+  /// Gets relays using the [Mullvad API][api], skipping inactive ones unless asked otherwise.
+  /// Returns the relays along with the number of malformed ones skipped.
   ///
   /// [api]: https://api.mullvad.net/app/documentation/#/paths/~1v1~1relays/get
-  async fn load_remote(&self) -> anyhow::Result<Vec<Relay>> {
+  async fn load_remote(&self) -> anyhow::Result<(Vec<Relay>, usize)> {
     let mut results = Vec::new();
+    let mut malformed = 0;
 
-    let response = reqwest::get("https://api.mullvad.net/app/v1/relays")
+    let body = self
+      .download("https://api.mullvad.net/app/v1/relays")
       .await
       .map_err(RelaysError::LoadRelaysFailed)?;
 
-    let data = response
-      .json::<Value>()
-      .await
-      .map_err(RelaysError::ParseResponseFailed)?;
-
-    let locations = get!(data, "locations", as_object);
-
-    let openvpn = get!(data, "openvpn", as_object);
-    let wireguard = get!(data, "wireguard", as_object);
+    let data =
+      serde_json::from_slice::<RelayList>(&body).map_err(RelaysError::ParseResponseFailed)?;
 
-    for (protocol, relays) in [
-      (Protocol::OpenVPN, get!(openvpn, "relays", as_array)),
-      (Protocol::WireGuard, get!(wireguard, "relays", as_array)),
+    for (role, protocol, relays) in [
+      (
+        RelayRole::Standard,
+        Some(Protocol::OpenVPN),
+        data.openvpn.relays,
+      ),
+      (
+        RelayRole::Standard,
+        Some(Protocol::WireGuard),
+        data.wireguard.relays,
+      ),
+      (RelayRole::Bridge, None, data.bridge.relays),
     ] {
       for relay in relays {
-        let location_code = get!(relay, "location", as_str).to_string();
-        let location = value!(locations, &location_code);
-
-        let coord = Coord::new(
-          get!(location, "latitude", as_f64),
-          get!(location, "longitude", as_f64),
-        );
+        // Relays pointing to unknown locations are as good as malformed.
+        let (relay, location) = match relay {
+          | Lenient::Parsed(relay) => {
+            match relay
+              .location_code()
+              .and_then(|code| data.locations.get(code))
+            {
+              | Some(location) => (relay, location),
+              | None => {
+                malformed += 1;
+                continue;
+              },
+            }
+          },
+          | Lenient::Malformed(..) => {
+            malformed += 1;
+            continue;
+          },
+        };
 
-        let distance = self.config.location.distance_to(&coord);
+        let coord = relay
+          .coord()
+          .unwrap_or_else(|| Coord::new(location.latitude, location.longitude));
+        let country_code = relay
+          .location_code()
+          .and_then(|code| code.split('-').next())
+          .map(str::to_string);
+        let distance = self
+          .config
+          .distance_model
+          .apply(self.config.location.distance_to(&coord));
 
         let relay = Relay {
-          coord,
+          coord: Some(coord),
           protocol,
-          distance,
-          ip: get!(relay, "ipv4_addr_in", as_str).to_string(),
-          city: get!(location, "city", as_str).to_string(),
-          country: get!(location, "country", as_str).to_string(),
-          is_active: get!(relay, "active", as_bool),
-          is_mullvad_owned: get!(relay, "owned", as_bool),
+          role,
+          features: Self::resolve_features(&relay),
+          distance: Some(distance),
+          ip: relay.ipv4_addr_in,
+          hostname: relay.hostname,
+          ipv6: relay.ipv6_addr_in,
+          city: location.city.clone(),
+          country: location.country.clone(),
+          country_code,
+          provider: relay.provider,
+          is_active: relay.active,
+          is_mullvad_owned: relay.owned,
+          target: None,
         };
 
-        // There's no reason to filter inactive relays.
-        if relay.is_active && self.filters.iter().all(|filter| filter.matches(&relay)) {
+        // There's usually no reason to ping inactive relays.
+        if relay.is_active || self.config.include_inactive {
           results.push(relay);
         }
       }
     }
 
-    Ok(results)
+    Ok((results, malformed))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const RELAYS: &str = r#"{
+    "countries": [{
+      "name": "Sweden",
+      "code": "se",
+      "cities": [{
+        "name": "Stockholm",
+        "latitude": 59.3289,
+        "longitude": 18.0649,
+        "relays": [
+          {
+            "hostname": "se-sto-wg-001",
+            "ipv4_addr_in": "185.65.135.67",
+            "active": true,
+            "owned": true,
+            "endpoint_data": { "wireguard": { "public_key": "..." } }
+          },
+          {
+            "hostname": "se-sto-ovpn-001",
+            "ipv4_addr_in": "185.65.134.66",
+            "active": true,
+            "owned": false,
+            "provider": "M247",
+            "endpoint_data": "openvpn"
+          }
+        ]
+      }]
+    }]
+  }"#;
+
+  fn config() -> RelaysLoaderConfig {
+    RelaysLoaderConfig {
+      location: Coord::new(59.3289, 18.0649),
+      include_inactive: false,
+      distance_model: DistanceModel::GreatCircle,
+      relays_file: None,
+      remote_fallback: false,
+      relays_url: None,
+    }
+  }
+
+  #[test]
+  fn parses_relays() {
+    let (relays, malformed) = RelaysLoader::parse_relays(RELAYS, &config()).unwrap();
+
+    assert_eq!(malformed, 0);
+    assert_eq!(relays.len(), 2);
+
+    let wireguard = &relays[0];
+    assert_eq!(wireguard.ip, "185.65.135.67");
+    assert_eq!(wireguard.hostname.as_deref(), Some("se-sto-wg-001"));
+    assert_eq!(wireguard.country, "Sweden");
+    assert_eq!(wireguard.country_code.as_deref(), Some("se"));
+    assert_eq!(wireguard.city, "Stockholm");
+    assert_eq!(wireguard.protocol, Some(Protocol::WireGuard));
+    assert_eq!(wireguard.role, RelayRole::Standard);
+    assert_eq!(wireguard.distance, Some(0.0));
+    assert_eq!(wireguard.provider, None);
+    assert!(wireguard.is_active);
+    assert!(wireguard.is_mullvad_owned);
+
+    let openvpn = &relays[1];
+    assert_eq!(openvpn.ip, "185.65.134.66");
+    assert_eq!(openvpn.hostname.as_deref(), Some("se-sto-ovpn-001"));
+    assert_eq!(openvpn.protocol, Some(Protocol::OpenVPN));
+    assert_eq!(openvpn.provider.as_deref(), Some("M247"));
+    assert!(!openvpn.is_mullvad_owned);
   }
 }
@@ -1,96 +1,1327 @@
+use std::cmp::Ordering;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::str::{Chars, FromStr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use dialoguer::console::Term;
+use dialoguer::Select;
+use serde_json::{json, Value};
 use tabled::builder::Builder;
-use tabled::settings::object::{Columns, Rows};
-use tabled::settings::{Alignment, Style};
+use tabled::settings::object::{Cell, Columns, Rows};
+use tabled::settings::{Alignment, Color, Style};
+use tabled::Table;
+use thiserror::Error;
+
+use crate::coord::Coord;
+use crate::pinger::{AddressFamily, RelayTimed};
+use crate::relays::{Feature, Relay};
+
+/// Domain shared by fully qualified Mullvad relay hostnames.
+pub const HOSTNAME_SUFFIX: &str = ".relays.mullvad.net";
+
+/// Maximum number of ticks in a sparkline, so long runs don't blow up the table width.
+const SPARKLINE_WIDTH: usize = 32;
 
-use crate::pinger::RelayTimed;
+#[derive(Debug, Error)]
+pub enum ReporterError {
+  #[error("Invalid score weights: {0}")]
+  InvalidScoreWeights(String),
+
+  #[error("Failed to write the results to {path}")]
+  WriteFailed { path: PathBuf, source: io::Error },
+
+  #[error("Only JSON and GeoJSON results can be saved to a directory")]
+  ArchiveUnsupported,
+
+  #[cfg(feature = "sqlite")]
+  #[error("Failed to write the results to the database at {path}")]
+  SqliteFailed {
+    path: PathBuf,
+    source: rusqlite::Error,
+  },
+}
 
 #[derive(Clone, Debug, Default, PartialEq)]
 pub enum SortBy {
   Country,
   City,
+  Hostname,
   MeanRTT,
   #[default]
   MedianRTT,
   Distance,
+  ClosestResponsive,
+  Score,
+  None,
 }
 
+/// Table border styles.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TableStyle {
+  #[default]
+  Rounded,
+  Sharp,
+  Minimal,
+  Markdown,
+  None,
+}
+
+/// Output formats for the results.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+  #[default]
+  Table,
+  Plain,
+  Json,
+  GeoJson,
+}
+
+/// Cells making up a report.
 #[derive(Debug)]
-pub struct Reporter {
+struct Layout {
+  /// Column names.
+  header: Vec<String>,
+  /// Cells of each relay, in the current order.
+  records: Vec<Vec<String>>,
+  /// Index of the first numeric column. Numeric columns are the trailing ones.
+  numeric_from: usize,
+}
+
+/// Weights of the components making up a relay score.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScoreWeights {
+  /// Weight of the median RTT.
+  rtt: f64,
+  /// Weight of the distance.
+  distance: f64,
+}
+
+impl Default for ScoreWeights {
+  fn default() -> Self {
+    Self {
+      rtt: 0.7,
+      distance: 0.3,
+    }
+  }
+}
+
+impl FromStr for ScoreWeights {
+  type Err = ReporterError;
+
+  /// Parses weights in the `rtt:0.7,distance:0.3` form. Omitted components get a weight of 0.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut weights = Self {
+      rtt: 0.0,
+      distance: 0.0,
+    };
+
+    for pair in s.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+      let (name, weight) = pair.split_once(':').ok_or_else(|| {
+        ReporterError::InvalidScoreWeights(format!("expected name:weight, got {pair}"))
+      })?;
+
+      let weight = weight
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|weight| weight.is_finite() && *weight >= 0.0)
+        .ok_or_else(|| ReporterError::InvalidScoreWeights(format!("invalid weight for {name}")))?;
+
+      match name.trim() {
+        | "rtt" => weights.rtt = weight,
+        | "distance" => weights.distance = weight,
+        | name => {
+          return Err(ReporterError::InvalidScoreWeights(format!(
+            "unknown component {name}"
+          )));
+        },
+      }
+    }
+
+    if weights.rtt + weights.distance == 0.0 {
+      return Err(ReporterError::InvalidScoreWeights(
+        "at least one weight must be positive".into(),
+      ));
+    }
+
+    Ok(weights)
+  }
+}
+
+/// Scores relays by combining their median RTT and distance, each normalized to `0..=1` across the
+/// whole result set. Lower is better. Unknown components count as the worst possible value.
+#[derive(Debug)]
+struct Scorer {
+  weights: ScoreWeights,
+  /// Minimum number of successful pings for a median RTT to count.
+  min_samples: usize,
+  /// Min and max median RTT (in seconds) across the result set.
+  rtt_bounds: Option<(f64, f64)>,
+  /// Min and max distance across the result set.
+  distance_bounds: Option<(f64, f64)>,
+}
+
+impl Scorer {
+  fn new(weights: ScoreWeights, min_samples: usize, timings: &[RelayTimed]) -> Self {
+    let bounds = |values: Vec<f64>| {
+      values.iter().fold(None, |bounds, &value| {
+        match bounds {
+          | None => Some((value, value)),
+          | Some((min, max)) => Some((value.min(min), value.max(max))),
+        }
+      })
+    };
+
+    let rtts = timings
+      .iter()
+      .filter_map(|timed| timed.rtt_median_reliable(min_samples))
+      .map(|rtt| rtt.as_secs_f64())
+      .collect();
+
+    let distances = timings
+      .iter()
+      .filter_map(|timed| timed.relay().distance)
+      .collect();
+
+    Self {
+      weights,
+      min_samples,
+      rtt_bounds: bounds(rtts),
+      distance_bounds: bounds(distances),
+    }
+  }
+
+  /// Computes the score of the given relay.
+  fn score(&self, timed: &RelayTimed) -> f64 {
+    let normalize = |value: Option<f64>, bounds: Option<(f64, f64)>| {
+      match value.zip(bounds) {
+        | Some((value, (min, max))) if max > min => (value - min) / (max - min),
+        | Some(..) => 0.0,
+        | None => 1.0,
+      }
+    };
+
+    let rtt = normalize(
+      timed
+        .rtt_median_reliable(self.min_samples)
+        .map(|rtt| rtt.as_secs_f64()),
+      self.rtt_bounds,
+    );
+
+    let distance = normalize(timed.relay().distance, self.distance_bounds);
+
+    self.weights.rtt * rtt + self.weights.distance * distance
+  }
+}
+
+#[derive(Debug)]
+pub struct ReporterConfig {
+  /// Field to sort by. Defaults to median RTT.
   sort_by: SortBy,
+  /// Whether to show the IPv6 address column. Defaults to `false`.
+  show_ipv6: bool,
+  /// Whether to show the hostname column. Defaults to `false`.
+  show_hostname: bool,
+  /// Suffix stripped from displayed hostnames. Defaults to [`HOSTNAME_SUFFIX`].
+  hostname_suffix: String,
+  /// Whether to show the column with hostnames targets were resolved from. Defaults to `false`.
+  show_target: bool,
+  /// Whether to show country codes instead of full country names, where known. Defaults to
+  /// `false`.
+  show_country_codes: bool,
+  /// Weights used when sorting by score. Defaults to 0.7 for RTT and 0.3 for distance.
+  score_weights: ScoreWeights,
+  /// Whether to mark the header of the sorted column with an asterisk. Defaults to `true`.
+  show_sort_marker: bool,
+  /// Whether to print only the summary instead of the per-relay results. Defaults to `false`.
+  summary_only: bool,
+  /// Whether to show a sparkline of per-ping timings. Defaults to `false`.
+  show_sparkline: bool,
+  /// Whether to list the timing of each successful ping. Defaults to `false`.
+  show_samples: bool,
+  /// Number of decimal places in RTT values (in ms). Defaults to 2.
+  precision: usize,
+  /// Whether to show the address family column. Defaults to `false`.
+  show_family: bool,
+  /// Whether to show the hosting organization column. Defaults to `false`.
+  show_org: bool,
+  /// Whether to show the supported features column. Defaults to `false`.
+  show_features: bool,
+  /// Table border style. Defaults to rounded.
+  table_style: TableStyle,
+  /// Whether to show the number of successful pings per relay. Defaults to `false`.
+  show_sample_count: bool,
+  /// Whether to show the outcome of the UDP probe. Defaults to `false`.
+  show_udp: bool,
+  /// Whether to show the spread between source port variants. Defaults to `false`.
+  show_variant_spread: bool,
+  /// Whether to show the moving average of median RTTs across rounds. Defaults to `false`.
+  show_ema: bool,
+  /// Character separating the integer and fractional parts of numbers. Defaults to `.`.
+  decimal_separator: char,
+  /// Minimum number of successful pings for a median RTT to be shown and ranked. Defaults to 1.
+  min_samples: usize,
+  /// Whether to color RTT values according to thresholds. Defaults to `false`.
+  colorize: bool,
+  /// RTTs up to this one are colored green. Defaults to 50 ms.
+  rtt_good: Duration,
+  /// RTTs up to this one are colored yellow, and above it red. Defaults to 150 ms.
+  rtt_warn: Duration,
+}
+
+impl ReporterConfig {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Set the field to sort by.
+  pub fn set_sort_by(mut self, sort_by: SortBy) -> Self {
+    self.sort_by = sort_by;
+    self
+  }
+
+  /// Set the weights used when sorting by score.
+  pub fn set_score_weights(mut self, score_weights: ScoreWeights) -> Self {
+    self.score_weights = score_weights;
+    self
+  }
+
+  /// Set whether to show the IPv6 address column.
+  pub fn set_show_ipv6(mut self, show_ipv6: bool) -> Self {
+    self.show_ipv6 = show_ipv6;
+    self
+  }
+
+  /// Set whether to show the hostname column.
+  pub fn set_show_hostname(mut self, show_hostname: bool) -> Self {
+    self.show_hostname = show_hostname;
+    self
+  }
+
+  /// Set the suffix stripped from displayed hostnames. An empty one keeps hostnames as they are.
+  pub fn set_hostname_suffix<S: Into<String>>(mut self, hostname_suffix: S) -> Self {
+    self.hostname_suffix = hostname_suffix.into();
+    self
+  }
+
+  /// Set whether to show the column with hostnames targets were resolved from.
+  pub fn set_show_target(mut self, show_target: bool) -> Self {
+    self.show_target = show_target;
+    self
+  }
+
+  /// Set whether to show country codes instead of full country names.
+  pub fn set_show_country_codes(mut self, show_country_codes: bool) -> Self {
+    self.show_country_codes = show_country_codes;
+    self
+  }
+
+  /// Set whether to show a sparkline of per-ping timings.
+  pub fn set_show_sparkline(mut self, show_sparkline: bool) -> Self {
+    self.show_sparkline = show_sparkline;
+    self
+  }
+
+  /// Set whether to list the timing of each successful ping.
+  pub fn set_show_samples(mut self, show_samples: bool) -> Self {
+    self.show_samples = show_samples;
+    self
+  }
+
+  /// Set whether to show the address family column.
+  pub fn set_show_family(mut self, show_family: bool) -> Self {
+    self.show_family = show_family;
+    self
+  }
+
+  /// Set whether to show the hosting organization column.
+  pub fn set_show_org(mut self, show_org: bool) -> Self {
+    self.show_org = show_org;
+    self
+  }
+
+  /// Set whether to show the supported features column.
+  pub fn set_show_features(mut self, show_features: bool) -> Self {
+    self.show_features = show_features;
+    self
+  }
+
+  /// Set whether to show the number of successful pings per relay.
+  pub fn set_show_sample_count(mut self, show_sample_count: bool) -> Self {
+    self.show_sample_count = show_sample_count;
+    self
+  }
+
+  /// Set whether to show the outcome of the UDP probe.
+  pub fn set_show_udp(mut self, show_udp: bool) -> Self {
+    self.show_udp = show_udp;
+    self
+  }
+
+  /// Set whether to show the moving average of median RTTs across rounds.
+  pub fn set_show_ema(mut self, show_ema: bool) -> Self {
+    self.show_ema = show_ema;
+    self
+  }
+
+  /// Set whether to show the spread between source port variants.
+  pub fn set_show_variant_spread(mut self, show_variant_spread: bool) -> Self {
+    self.show_variant_spread = show_variant_spread;
+    self
+  }
+
+  /// Set the table border style.
+  pub fn set_table_style(mut self, table_style: TableStyle) -> Self {
+    self.table_style = table_style;
+    self
+  }
+
+  /// Set the number of decimal places in RTT values.
+  pub fn set_precision(mut self, precision: usize) -> Self {
+    self.precision = precision;
+    self
+  }
+
+  /// Set the character separating the integer and fractional parts of numbers.
+  pub fn set_decimal_separator(mut self, decimal_separator: char) -> Self {
+    self.decimal_separator = decimal_separator;
+    self
+  }
+
+  /// Set the minimum number of successful pings for a median RTT to be shown and ranked.
+  pub fn set_min_samples(mut self, min_samples: usize) -> Self {
+    self.min_samples = min_samples;
+    self
+  }
+
+  /// Set whether to color RTT values according to thresholds.
+  pub fn set_colorize(mut self, colorize: bool) -> Self {
+    self.colorize = colorize;
+    self
+  }
+
+  /// Set the thresholds for coloring RTT values green and yellow. RTTs above `warn` are colored
+  /// red.
+  pub fn set_rtt_thresholds(mut self, good: Duration, warn: Duration) -> Self {
+    self.rtt_good = good;
+    self.rtt_warn = warn;
+    self
+  }
+
+  /// Set whether to mark the header of the sorted column.
+  pub fn set_show_sort_marker(mut self, show_sort_marker: bool) -> Self {
+    self.show_sort_marker = show_sort_marker;
+    self
+  }
+
+  /// Set whether to print only the summary instead of the per-relay results.
+  pub fn set_summary_only(mut self, summary_only: bool) -> Self {
+    self.summary_only = summary_only;
+    self
+  }
+}
+
+impl Default for ReporterConfig {
+  fn default() -> Self {
+    Self {
+      sort_by: SortBy::default(),
+      show_ipv6: false,
+      show_hostname: false,
+      hostname_suffix: HOSTNAME_SUFFIX.to_string(),
+      show_target: false,
+      show_country_codes: false,
+      score_weights: ScoreWeights::default(),
+      show_sort_marker: true,
+      summary_only: false,
+      show_sparkline: false,
+      show_samples: false,
+      precision: 2,
+      show_family: false,
+      show_org: false,
+      show_features: false,
+      table_style: TableStyle::default(),
+      show_sample_count: false,
+      show_udp: false,
+      show_variant_spread: false,
+      show_ema: false,
+      decimal_separator: '.',
+      min_samples: 1,
+      colorize: false,
+      rtt_good: Duration::from_millis(50),
+      rtt_warn: Duration::from_millis(150),
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct Reporter {
+  config: ReporterConfig,
   timings: Vec<RelayTimed>,
 }
 
 impl Reporter {
-  pub fn new(timings: Vec<RelayTimed>, sort_by: SortBy) -> Self {
-    Self { sort_by, timings }
+  pub fn new<T>(timings: T, config: ReporterConfig) -> Self
+  where
+    T: IntoIterator<Item = RelayTimed>,
+  {
+    Self {
+      config,
+      timings: timings.into_iter().collect(),
+    }
   }
 
-  /// Sorts the relay timings.
+  /// Sorts the relay timings. The sort is stable, so relays that compare equal keep the order they
+  /// were encountered in.
   pub fn sort(&mut self) {
+    let scorer = Scorer::new(
+      self.config.score_weights,
+      self.config.min_samples,
+      &self.timings,
+    );
+
+    let min_samples = self.config.min_samples;
+    let median = |timed: &RelayTimed| timed.rtt_median_reliable(min_samples);
+
     self.timings.sort_by(|a_timed, b_timed| {
       let a_relay = a_timed.relay();
       let b_relay = b_timed.relay();
 
-      match self.sort_by {
+      match self.config.sort_by {
         | SortBy::Country => a_relay.country.cmp(&b_relay.country),
         | SortBy::City => a_relay.city.cmp(&b_relay.city),
-        | SortBy::MeanRTT => a_timed.rtt_mean().cmp(&b_timed.rtt_mean()),
-        | SortBy::MedianRTT => a_timed.rtt_median().cmp(&b_timed.rtt_median()),
-        | SortBy::Distance => a_relay.distance.total_cmp(&b_relay.distance),
+        | SortBy::Hostname => cmp_hostname(a_relay, b_relay),
+        // When RTTs tie, which is common with coarse timings, fall back to the other RTT statistic
+        // and then to distance.
+        | SortBy::MeanRTT => {
+          cmp_rtt(a_timed.rtt_mean(), b_timed.rtt_mean())
+            .then_with(|| cmp_rtt(median(a_timed), median(b_timed)))
+            .then_with(|| cmp_distance(a_relay.distance, b_relay.distance))
+        },
+        | SortBy::MedianRTT => {
+          cmp_rtt(median(a_timed), median(b_timed))
+            .then_with(|| cmp_rtt(a_timed.rtt_mean(), b_timed.rtt_mean()))
+            .then_with(|| cmp_distance(a_relay.distance, b_relay.distance))
+        },
+        | SortBy::Distance => cmp_distance(a_relay.distance, b_relay.distance),
+        // Nearest relays first, but only among the ones that responded at all.
+        | SortBy::ClosestResponsive => {
+          let a_median = median(a_timed);
+          let b_median = median(b_timed);
+
+          b_median
+            .is_some()
+            .cmp(&a_median.is_some())
+            .then_with(|| cmp_distance(a_relay.distance, b_relay.distance))
+            .then_with(|| cmp_rtt(a_median, b_median))
+        },
+        | SortBy::Score => scorer.score(a_timed).total_cmp(&scorer.score(b_timed)),
+        // Keep the load order as is.
+        | SortBy::None => Ordering::Equal,
       }
     });
   }
 
-  /// Builds the report table and prints it to stdout.
+  /// Drops relays within `km` kilometers of a relay ranked higher in the current order, so the
+  /// results are spread out geographically. Relays without coordinates are always kept. Returns the
+  /// number of relays dropped.
+  pub fn spread(&mut self, km: f64) -> usize {
+    let before = self.timings.len();
+    let mut selected: Vec<Coord> = Vec::new();
+
+    self.timings.retain(|timed| {
+      match timed.relay().coord {
+        | Some(coord) if selected.iter().any(|other| other.distance_to(&coord) < km) => false,
+        | Some(coord) => {
+          selected.push(coord);
+          true
+        },
+        | None => true,
+      }
+    });
+
+    before - self.timings.len()
+  }
+
+  /// Returns the first relay in the current order with a reliable median RTT, or, failing that,
+  /// the first one that responded at least once.
+  pub fn fastest(&self) -> Option<&RelayTimed> {
+    self
+      .timings
+      .iter()
+      .find(|timed| self.median(timed).is_some())
+      .or_else(|| {
+        self
+          .timings
+          .iter()
+          .find(|timed| timed.rtt_median().is_some())
+      })
+  }
+
+  /// Prints the IP of the fastest relay on a single line, suitable for scripting.
+  pub fn report_fastest(&self) -> bool {
+    match self.fastest() {
+      | Some(timed) => {
+        println!("{}", timed.relay().ip);
+        true
+      },
+      | None => false,
+    }
+  }
+
+  /// Prints the `mullvad` CLI command selecting the first responsive relay with a known hostname.
+  pub fn report_mullvad_cmd(&self) -> bool {
+    let command = self
+      .timings
+      .iter()
+      .filter(|timed| timed.rtt_median().is_some())
+      .find_map(|timed| self.hostname(timed.relay()).and_then(mullvad_cmd));
+
+    match command {
+      | Some(command) => {
+        println!("{command}");
+        true
+      },
+      | None => false,
+    }
+  }
+
+  /// Lets the user pick a relay from the results in the current order with arrow keys and prints
+  /// the chosen one along with the `mullvad` CLI command selecting it, if its hostname is known.
+  /// Returns `false` if nothing was picked.
+  pub fn report_selection(&self) -> io::Result<bool> {
+    if self.timings.is_empty() {
+      return Ok(false);
+    }
+
+    let items = self
+      .timings
+      .iter()
+      .map(|timed| timed.to_string())
+      .collect::<Vec<_>>();
+
+    let selection = Select::new()
+      .with_prompt("Pick a relay (Esc to quit)")
+      .items(&items)
+      .default(0)
+      .interact_on_opt(&Term::stderr())
+      .map_err(|dialoguer::Error::IO(err)| err)?;
+
+    let Some(timed) = selection.map(|idx| &self.timings[idx]) else {
+      return Ok(false);
+    };
+
+    println!("{timed}");
+
+    if let Some(command) = self.hostname(timed.relay()).and_then(mullvad_cmd) {
+      println!("{command}");
+    }
+
+    Ok(true)
+  }
+
+  /// Writes the results in the current order as a JSON array.
+  pub fn write_json<W: Write>(&self, writer: W) -> io::Result<()> {
+    let millis = |rtt: Duration| rtt.as_secs_f64() * 1_000.0;
+
+    let results = self
+      .ranked()
+      .map(|(rank, timed)| {
+        let relay = timed.relay();
+
+        json!({
+          "rank": rank,
+          "ip": relay.ip,
+          "hostname": relay.hostname,
+          "target": relay.target,
+          "protocol": relay.protocol.map(|protocol| protocol.to_string()),
+          "country": relay.country,
+          "city": relay.city,
+          "distance_km": relay.distance,
+          "rtt_median_ms": self.median(timed).map(millis),
+          "rtt_mean_ms": timed.rtt_mean().map(millis),
+          "timings_ms": timed.timings().iter().copied().map(millis).collect::<Vec<_>>(),
+          "probe": timed.probe().to_string(),
+        })
+      })
+      .collect::<Vec<_>>();
+
+    serde_json::to_writer_pretty(writer, &results)?;
+
+    Ok(())
+  }
+
+  /// Returns the JSON Schema of the output of [`Reporter::write_json`]. A test checks the output
+  /// against it.
+  pub fn json_schema() -> Value {
+    let nullable = |kind: &str| json!({ "type": [kind, "null"] });
+
+    json!({
+      "$schema": "https://json-schema.org/draft/2020-12/schema",
+      "title": "pingmole results",
+      "description": "Relays in the order they were ranked.",
+      "type": "array",
+      "items": {
+        "type": "object",
+        "properties": {
+          "rank": { "type": "integer", "minimum": 1 },
+          "ip": { "type": "string" },
+          "hostname": nullable("string"),
+          "target": nullable("string"),
+          "protocol": { "enum": ["OpenVPN", "WireGuard", null] },
+          "country": { "type": "string" },
+          "city": { "type": "string" },
+          "distance_km": nullable("number"),
+          "rtt_median_ms": nullable("number"),
+          "rtt_mean_ms": nullable("number"),
+          "timings_ms": { "type": "array", "items": { "type": "number" } },
+          "probe": { "enum": ["connect", "roundtrip"] },
+        },
+        "required": [
+          "rank", "ip", "hostname", "target", "protocol", "country", "city", "distance_km",
+          "rtt_median_ms", "rtt_mean_ms", "timings_ms", "probe",
+        ],
+        "additionalProperties": false,
+      },
+    })
+  }
+
+  /// Writes the results as a GeoJSON `FeatureCollection`, with a point for the reference location,
+  /// if known, and one for each relay with known coordinates.
+  pub fn write_geojson<W: Write>(&self, writer: W, location: Option<Coord>) -> io::Result<()> {
+    let millis = |rtt: Duration| rtt.as_secs_f64() * 1_000.0;
+
+    let origin = location.map(|coord| geojson_point(coord, json!({ "kind": "location" })));
+
+    let relays = self.ranked().filter_map(|(rank, timed)| {
+      let relay = timed.relay();
+
+      relay.coord.map(|coord| {
+        geojson_point(
+          coord,
+          json!({
+            "kind": "relay",
+            "rank": rank,
+            "ip": relay.ip,
+            "hostname": relay.hostname,
+            "country": relay.country,
+            "city": relay.city,
+            "distance_km": relay.distance,
+            "rtt_median_ms": self.median(timed).map(millis),
+            "rtt_mean_ms": timed.rtt_mean().map(millis),
+          }),
+        )
+      })
+    });
+
+    let collection = json!({
+      "type": "FeatureCollection",
+      "features": origin.into_iter().chain(relays).collect::<Vec<_>>(),
+    });
+
+    serde_json::to_writer_pretty(writer, &collection)?;
+
+    Ok(())
+  }
+
+  /// Writes the results in the given format to a timestamped `pingmole-<unix time in ms>` file in
+  /// `dir`, creating the directory if it's missing. Files written within the same millisecond get
+  /// a numbered suffix rather than overwriting each other. Only JSON and GeoJSON can be saved.
+  /// Returns the path of the written file.
+  pub fn archive(
+    &self,
+    dir: &Path,
+    format: OutputFormat,
+    location: Option<Coord>,
+  ) -> Result<PathBuf, ReporterError> {
+    let extension = match format {
+      | OutputFormat::Json => "json",
+      | OutputFormat::GeoJson => "geojson",
+      | OutputFormat::Table | OutputFormat::Plain => return Err(ReporterError::ArchiveUnsupported),
+    };
+
+    let timestamp = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_millis();
+
+    let (path, file) =
+      create_new_file(dir, &format!("pingmole-{timestamp}"), extension).map_err(|source| {
+        ReporterError::WriteFailed {
+          path: dir.to_owned(),
+          source,
+        }
+      })?;
+
+    let write = || {
+      let mut writer = BufWriter::new(file);
+
+      if format == OutputFormat::GeoJson {
+        self.write_geojson(&mut writer, location)?;
+      } else {
+        self.write_json(&mut writer)?;
+      }
+
+      writer.flush()
+    };
+
+    write().map_err(|source| {
+      ReporterError::WriteFailed {
+        path: path.clone(),
+        source,
+      }
+    })?;
+
+    Ok(path)
+  }
+
+  /// Appends the results in the current order to the `results` table of the SQLite database at
+  /// `path`, all stamped with the same run time. The database and table are created if missing.
+  #[cfg(feature = "sqlite")]
+  pub fn append_sqlite(&self, path: &Path) -> Result<usize, ReporterError> {
+    let millis = |rtt: Duration| rtt.as_secs_f64() * 1_000.0;
+
+    let run_at = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_secs() as i64;
+
+    let write = || -> rusqlite::Result<usize> {
+      let mut conn = rusqlite::Connection::open(path)?;
+
+      conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS results (
+          run_at INTEGER NOT NULL,
+          rank INTEGER NOT NULL,
+          ip TEXT NOT NULL,
+          hostname TEXT,
+          protocol TEXT,
+          country TEXT NOT NULL,
+          city TEXT NOT NULL,
+          distance_km REAL,
+          rtt_median_ms REAL,
+          rtt_mean_ms REAL,
+          loss_pct REAL,
+          samples INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS results_ip_run_at ON results (ip, run_at);",
+      )?;
+
+      // A single transaction keeps a run either fully recorded or not at all.
+      let tx = conn.transaction()?;
+
+      {
+        let mut insert = tx.prepare(
+          "INSERT INTO results (
+            run_at, rank, ip, hostname, protocol, country, city, distance_km, rtt_median_ms,
+            rtt_mean_ms, loss_pct, samples
+          ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+        )?;
+
+        for (rank, timed) in self.ranked() {
+          let relay = timed.relay();
+
+          insert.execute(rusqlite::params![
+            run_at,
+            rank as i64,
+            relay.ip,
+            relay.hostname,
+            relay.protocol.map(|protocol| protocol.to_string()),
+            relay.country,
+            relay.city,
+            relay.distance,
+            self.median(timed).map(millis),
+            timed.rtt_mean().map(millis),
+            timed.loss(),
+            timed.timings().len() as i64,
+          ])?;
+        }
+      }
+
+      tx.commit()?;
+
+      Ok(self.timings.len())
+    };
+
+    write().map_err(|source| {
+      ReporterError::SqliteFailed {
+        path: path.to_path_buf(),
+        source,
+      }
+    })
+  }
+
+  /// Returns a one-line summary of the results: how many relays responded, the fastest one in the
+  /// current order and the mean distance of relays with a known one.
+  pub fn summary(&self) -> String {
+    let responded = self
+      .timings
+      .iter()
+      .filter(|timed| timed.rtt_median().is_some())
+      .count();
+
+    let best = self.fastest().map_or("none".to_string(), |timed| {
+      let rtt = self.median(timed).or_else(|| timed.rtt_median());
+      let rtt = rtt.map_or("n/a".to_string(), |rtt| self.format_rtt(rtt, false));
+
+      format!("{} at {rtt}", timed.relay())
+    });
+
+    let distances = self
+      .timings
+      .iter()
+      .filter_map(|timed| timed.relay().distance)
+      .collect::<Vec<_>>();
+
+    let mean_distance = if distances.is_empty() {
+      "n/a".to_string()
+    } else {
+      let mean = distances.iter().sum::<f64>() / distances.len() as f64;
+      format!("~{} km", mean.round())
+    };
+
+    format!(
+      "{responded}/{} relays responded, best: {best}, mean distance: {mean_distance}",
+      self.timings.len()
+    )
+  }
+
+  /// Prints the summary to stdout.
+  fn report_summary(&self) {
+    println!("{}", self.summary());
+  }
+
+  /// Builds the report table and prints it to stdout. Prints only the summary if configured so.
   pub fn report(&self) {
+    if self.config.summary_only {
+      return self.report_summary();
+    }
+
+    let layout = self.layout(false);
+    let numeric_from = layout.numeric_from;
+    let sparkline_column = self
+      .config
+      .show_sparkline
+      .then(|| layout.header.iter().position(|name| name == "Timings"))
+      .flatten();
+
     let mut builder = Builder::default();
 
-    builder.push_record(self.columns(vec![
-      ("#", None),
-      ("IP", None),
-      ("Protocol", None),
-      ("Country", Some(SortBy::Country)),
-      ("City", Some(SortBy::City)),
-      ("Distance", Some(SortBy::Distance)),
-      ("RTT median", Some(SortBy::MedianRTT)),
-      ("RTT mean", Some(SortBy::MeanRTT)),
-    ]));
+    builder.push_record(layout.header);
+
+    for record in layout.records {
+      builder.push_record(record);
+    }
+
+    let mut table = builder.build();
 
-    for (idx, timed) in self.timings.iter().enumerate() {
+    table
+      .modify(Columns::new(numeric_from..), Alignment::right())
+      .modify(Rows::new(..1), Alignment::left());
+
+    // RTT median and mean follow the distance column. Relays that never responded stay uncolored.
+    if self.config.colorize {
+      for (row, timed) in self.ranked() {
+        let rtts = [self.median(timed), timed.rtt_mean()];
+
+        for (offset, rtt) in rtts.into_iter().enumerate() {
+          if let Some(rtt) = rtt {
+            table.modify(
+              Cell::new(row, numeric_from + 1 + offset),
+              self.rtt_color(rtt),
+            );
+          }
+        }
+
+        // The sparkline takes the color of the median, like the RTT cells.
+        if let (Some(column), Some(median)) = (sparkline_column, self.median(timed)) {
+          table.modify(Cell::new(row, column), self.rtt_color(median));
+        }
+      }
+    }
+
+    self.apply_style(&mut table);
+
+    println!("{table}");
+  }
+
+  /// Prints a compact table with the fastest relay of each country, by median RTT. Countries
+  /// without any responding relay are left out.
+  pub fn report_per_country_best(&self) {
+    let mut best: Vec<(&RelayTimed, Duration)> = Vec::new();
+
+    for timed in &self.timings {
+      let Some(median) = self.median(timed) else {
+        continue;
+      };
+
+      let country = &timed.relay().country;
+
+      match best
+        .iter_mut()
+        .find(|(other, _)| &other.relay().country == country)
+      {
+        | Some(entry) if median < entry.1 => *entry = (timed, median),
+        | Some(..) => {},
+        | None => best.push((timed, median)),
+      }
+    }
+
+    best.sort_by_key(|(_, median)| *median);
+
+    let mut builder = Builder::default();
+
+    builder.push_record(["Country", "IP", "City", "RTT median"]);
+
+    for (timed, median) in best {
       let relay = timed.relay();
-      let distance = relay.distance.round();
-      let rtt_mean = timed.rtt_mean().unwrap_or_default().as_secs_f64() * 1_000.0;
-      let rtt_median = timed.rtt_median().unwrap_or_default().as_secs_f64() * 1_000.0;
+
+      let country = match &relay.country_code {
+        | Some(code) if self.config.show_country_codes => code.to_uppercase(),
+        | _ => relay.country.clone(),
+      };
 
       builder.push_record([
-        (idx + 1).to_string(),
-        relay.ip.to_string(),
-        relay.protocol.to_string(),
-        relay.country.clone(),
+        country,
+        relay.ip.clone(),
         relay.city.clone(),
-        format!("~{distance} km"),
-        format!("{rtt_median:.2} ms"),
-        format!("{rtt_mean:.2} ms"),
+        self.format_rtt(median, false),
       ]);
     }
 
     let mut table = builder.build();
 
     table
-      .modify(Columns::new(5..), Alignment::right())
-      .modify(Rows::new(..1), Alignment::left())
-      .with(Style::rounded());
+      .modify(Columns::new(3..), Alignment::right())
+      .modify(Rows::new(..1), Alignment::left());
+
+    self.apply_style(&mut table);
 
     println!("{table}");
   }
 
+  /// Applies the configured border style to the table.
+  fn apply_style(&self, table: &mut Table) {
+    match self.config.table_style {
+      | TableStyle::Rounded => table.with(Style::rounded()),
+      | TableStyle::Sharp => table.with(Style::sharp()),
+      | TableStyle::Minimal => table.with(Style::blank()),
+      | TableStyle::Markdown => table.with(Style::markdown()),
+      | TableStyle::None => table.with(Style::empty()),
+    };
+  }
+
+  /// Prints one relay per line, with columns aligned by spaces and no header or box drawing. Prints
+  /// only the summary if configured so.
+  pub fn report_plain(&self) {
+    if self.config.summary_only {
+      return self.report_summary();
+    }
+
+    let layout = self.layout(true);
+
+    let widths = layout
+      .records
+      .iter()
+      .fold(Vec::new(), |mut widths: Vec<usize>, record| {
+        widths.resize(record.len(), 0);
+
+        for (width, cell) in widths.iter_mut().zip(record) {
+          *width = (*width).max(cell.chars().count());
+        }
+
+        widths
+      });
+
+    for record in &layout.records {
+      let line = record
+        .iter()
+        .zip(&widths)
+        .enumerate()
+        .map(|(column, (cell, width))| {
+          // The rank is numeric as well, so it's aligned to the right with the trailing columns.
+          if column == 0 || column >= layout.numeric_from {
+            format!("{cell:>width$}")
+          } else {
+            format!("{cell:<width$}")
+          }
+        })
+        .collect::<Vec<_>>()
+        .join("  ");
+
+      println!("  {}", line.trim_end());
+    }
+  }
+
+  /// Extracts the cells to report: the header and one record per relay. With `compact` set, units
+  /// are attached to the values without spaces, e.g. `5.53ms`.
+  fn layout(&self, compact: bool) -> Layout {
+    let mut columns = vec![("#", None), ("IP", None)];
+
+    if self.config.show_hostname {
+      columns.push(("Hostname", Some(SortBy::Hostname)));
+    }
+
+    if self.config.show_target {
+      columns.push(("Target", None));
+    }
+
+    if self.config.show_ipv6 {
+      columns.push(("IPv6", None));
+    }
+
+    columns.push(("Protocol", None));
+
+    if self.config.show_family {
+      columns.push(("Family", None));
+    }
+
+    columns.extend([
+      ("Country", Some(SortBy::Country)),
+      ("City", Some(SortBy::City)),
+    ]);
+
+    if self.config.show_org {
+      columns.push(("Org", None));
+    }
+
+    if self.config.show_features {
+      columns.push(("Features", None));
+    }
+
+    if self.config.show_udp {
+      columns.push(("UDP", None));
+    }
+
+    // Numeric columns are the trailing ones and get aligned to the right.
+    let numeric_from = columns.len();
+
+    columns.extend([
+      ("Distance", Some(SortBy::Distance)),
+      ("RTT median", Some(SortBy::MedianRTT)),
+      ("RTT mean", Some(SortBy::MeanRTT)),
+    ]);
+
+    if self.config.show_ema {
+      columns.push(("RTT EMA", None));
+    }
+
+    if self.config.show_variant_spread {
+      columns.push(("Port spread", None));
+    }
+
+    if self.config.show_sample_count {
+      columns.push(("Samples", None));
+    }
+
+    if self.config.show_sparkline {
+      columns.push(("Timings", None));
+    }
+
+    if self.config.show_samples {
+      columns.push(("Pings (ms)", None));
+    }
+
+    // The score only makes sense when sorting by it.
+    let scorer = (self.config.sort_by == SortBy::Score).then(|| {
+      Scorer::new(
+        self.config.score_weights,
+        self.config.min_samples,
+        &self.timings,
+      )
+    });
+
+    if scorer.is_some() {
+      columns.push(("Score", Some(SortBy::Score)));
+    }
+
+    let mut records = Vec::new();
+
+    for (rank, timed) in self.ranked() {
+      let relay = timed.relay();
+      let distance = relay.distance.map_or("-".to_string(), |distance| {
+        if compact {
+          format!("{}km", distance.round())
+        } else {
+          format!("~{} km", distance.round())
+        }
+      });
+      let protocol = relay
+        .protocol
+        .map_or("-".to_string(), |protocol| protocol.to_string());
+      // Relays that never responded have no RTT, which must not look like an instant response.
+      let rtt_mean = timed
+        .rtt_mean()
+        .map_or("-".to_string(), |rtt| self.format_rtt(rtt, compact));
+      let rtt_median = self
+        .median(timed)
+        .map_or("-".to_string(), |rtt| self.format_rtt(rtt, compact));
+
+      let mut record = vec![rank.to_string(), relay.ip.to_string()];
+
+      if self.config.show_hostname {
+        record.push(self.hostname(relay).unwrap_or("-").to_string());
+      }
+
+      if self.config.show_target {
+        record.push(relay.target.clone().unwrap_or_else(|| "-".to_string()));
+      }
+
+      if self.config.show_ipv6 {
+        record.push(relay.ipv6.clone().unwrap_or_else(|| "-".to_string()));
+      }
+
+      record.push(protocol);
+
+      if self.config.show_family {
+        record.push(families(timed.families()));
+      }
+
+      let country = match &relay.country_code {
+        | Some(code) if self.config.show_country_codes => code.to_uppercase(),
+        | _ => relay.country.clone(),
+      };
+
+      record.extend([country, relay.city.clone()]);
+
+      if self.config.show_org {
+        record.push(relay.provider.clone().unwrap_or_else(|| "-".to_string()));
+      }
+
+      if self.config.show_features {
+        record.push(features(&relay.features));
+      }
+
+      if self.config.show_udp {
+        record.push(timed.udp().map_or("-".to_string(), |udp| udp.to_string()));
+      }
+
+      record.extend([distance, rtt_median, rtt_mean]);
+
+      if self.config.show_ema {
+        record.push(
+          timed
+            .ema()
+            .map_or("-".to_string(), |ema| self.format_rtt(ema, compact)),
+        );
+      }
+
+      if self.config.show_variant_spread {
+        record.push(
+          timed
+            .variant_spread()
+            .map_or("-".to_string(), |spread| self.format_rtt(spread, compact)),
+        );
+      }
+
+      if self.config.show_sample_count {
+        record.push(timed.timings().len().to_string());
+      }
+
+      if self.config.show_sparkline {
+        record.push(sparkline(timed.timings()));
+      }
+
+      if self.config.show_samples {
+        record.push(self.format_samples(timed.timings(), compact));
+      }
+
+      if let Some(scorer) = &scorer {
+        record.push(self.format_decimal(scorer.score(timed), 3));
+      }
+
+      records.push(record);
+    }
+
+    Layout {
+      header: self.columns(columns),
+      records,
+      numeric_from,
+    }
+  }
+
+  /// Returns the hostname of the relay to display, with the configured suffix stripped.
+  fn hostname<'a>(&self, relay: &'a Relay) -> Option<&'a str> {
+    let hostname = relay.hostname.as_deref()?;
+
+    Some(
+      hostname
+        .strip_suffix(self.config.hostname_suffix.as_str())
+        .unwrap_or(hostname),
+    )
+  }
+
+  /// Formats an RTT in milliseconds with the configured precision.
+  fn format_rtt(&self, rtt: Duration, compact: bool) -> String {
+    let rtt = self.format_decimal(rtt.as_secs_f64() * 1_000.0, self.config.precision);
+
+    if compact {
+      format!("{rtt}ms")
+    } else {
+      format!("{rtt} ms")
+    }
+  }
+
+  /// Lists timings in milliseconds, without units to keep the column narrow. In compact mode they
+  /// are joined without spaces, so the list stays a single field.
+  fn format_samples(&self, timings: &[Duration], compact: bool) -> String {
+    if timings.is_empty() {
+      return "-".to_string();
+    }
+
+    let separator = if compact { ";" } else { "; " };
+
+    timings
+      .iter()
+      .map(|rtt| self.format_decimal(rtt.as_secs_f64() * 1_000.0, self.config.precision))
+      .collect::<Vec<_>>()
+      .join(separator)
+  }
+
+  /// Gets the median RTT of the relay if it's based on enough successful pings.
+  fn median(&self, timed: &RelayTimed) -> Option<Duration> {
+    timed.rtt_median_reliable(self.config.min_samples)
+  }
+
+  /// Picks the color for an RTT according to the configured thresholds.
+  fn rtt_color(&self, rtt: Duration) -> Color {
+    if rtt <= self.config.rtt_good {
+      Color::FG_GREEN
+    } else if rtt <= self.config.rtt_warn {
+      Color::FG_YELLOW
+    } else {
+      Color::FG_RED
+    }
+  }
+
+  /// Formats a number with the given number of decimal places and the configured separator.
+  fn format_decimal(&self, value: f64, precision: usize) -> String {
+    let value = format!("{value:.precision$}");
+
+    match self.config.decimal_separator {
+      | '.' => value,
+      | separator => value.replace('.', &separator.to_string()),
+    }
+  }
+
+  /// Pairs each relay timing with its 1-based rank in the current (sorted) order.
+  fn ranked(&self) -> impl Iterator<Item = (usize, &RelayTimed)> {
+    self
+      .timings
+      .iter()
+      .enumerate()
+      .map(|(idx, timed)| (idx + 1, timed))
+  }
+
   /// Processes column names and marks the one being sorted.
   fn columns(&self, fields: Vec<(&str, Option<SortBy>)>) -> Vec<String> {
     fields
       .into_iter()
       .map(|field| {
         if let Some(sort_by) = field.1 {
-          if sort_by == self.sort_by {
+          if self.config.show_sort_marker && sort_by == self.config.sort_by {
             format!("{} *", field.0)
           } else {
             field.0.to_string()
@@ -102,3 +1333,523 @@ impl Reporter {
       .collect()
   }
 }
+
+/// Creates a new `<stem>.<extension>` file in `dir`, creating the directory if needed. If the file
+/// already exists, a numbered suffix is added to the stem, e.g. `<stem>-1.<extension>`.
+fn create_new_file(dir: &Path, stem: &str, extension: &str) -> io::Result<(PathBuf, File)> {
+  fs::create_dir_all(dir)?;
+
+  let mut attempt = 0;
+
+  loop {
+    let name = match attempt {
+      | 0 => format!("{stem}.{extension}"),
+      | _ => format!("{stem}-{attempt}.{extension}"),
+    };
+
+    let path = dir.join(name);
+
+    match OpenOptions::new().write(true).create_new(true).open(&path) {
+      | Ok(file) => return Ok((path, file)),
+      | Err(err) if err.kind() == io::ErrorKind::AlreadyExists => attempt += 1,
+      | Err(err) => return Err(err),
+    }
+  }
+}
+
+/// Builds the `mullvad relay set location` command for the relay with the given hostname. Country
+/// and city codes are taken from the hostname, which follows the
+/// `<country>-<city>-<type>-<number>` pattern.
+fn mullvad_cmd(hostname: &str) -> Option<String> {
+  let mut codes = hostname.split('-');
+
+  let country = codes.next()?;
+  let city = codes.next()?;
+
+  Some(format!(
+    "mullvad relay set location {country} {city} {hostname}"
+  ))
+}
+
+/// Builds a GeoJSON point feature. Note that GeoJSON puts longitude first.
+fn geojson_point(coord: Coord, properties: Value) -> Value {
+  json!({
+    "type": "Feature",
+    "geometry": {
+      "type": "Point",
+      "coordinates": [coord.longitude(), coord.latitude()],
+    },
+    "properties": properties,
+  })
+}
+
+/// Compares hostnames in natural order, so `se-sto-wg-2` comes before `se-sto-wg-10`. Relays
+/// without a hostname go last and are compared by IP.
+fn cmp_hostname(a: &Relay, b: &Relay) -> Ordering {
+  match (&a.hostname, &b.hostname) {
+    | (Some(a), Some(b)) => cmp_natural(a, b),
+    | (Some(..), None) => Ordering::Less,
+    | (None, Some(..)) => Ordering::Greater,
+    | (None, None) => cmp_natural(&a.ip, &b.ip),
+  }
+}
+
+/// Compares strings treating runs of digits as numbers and ignoring ASCII case. Strings that only
+/// differ in case or leading zeros are then compared as is, so the order is still total.
+fn cmp_natural(a: &str, b: &str) -> Ordering {
+  let mut a_chars = a.chars().peekable();
+  let mut b_chars = b.chars().peekable();
+
+  let ordering = loop {
+    match (a_chars.peek().copied(), b_chars.peek().copied()) {
+      | (None, None) => break Ordering::Equal,
+      | (None, Some(..)) => break Ordering::Less,
+      | (Some(..), None) => break Ordering::Greater,
+      | (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+        let x = take_number(&mut a_chars);
+        let y = take_number(&mut b_chars);
+        let x = x.trim_start_matches('0');
+        let y = y.trim_start_matches('0');
+
+        // Compare by magnitude first, ignoring leading zeros.
+        let ordering = x.len().cmp(&y.len()).then_with(|| x.cmp(y));
+
+        if ordering != Ordering::Equal {
+          break ordering;
+        }
+      },
+      | (Some(x), Some(y)) => {
+        let ordering = x.to_ascii_lowercase().cmp(&y.to_ascii_lowercase());
+
+        if ordering != Ordering::Equal {
+          break ordering;
+        }
+
+        a_chars.next();
+        b_chars.next();
+      },
+    }
+  };
+
+  ordering.then_with(|| a.cmp(b))
+}
+
+/// Consumes a run of digits from the iterator.
+fn take_number(chars: &mut Peekable<Chars>) -> String {
+  let mut number = String::new();
+
+  while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+    number.push(digit);
+  }
+
+  number
+}
+
+/// Compares RTTs, putting unknown ones, i.e. of unreachable relays, last.
+fn cmp_rtt(a: Option<Duration>, b: Option<Duration>) -> Ordering {
+  match (a, b) {
+    | (Some(a), Some(b)) => a.cmp(&b),
+    | (Some(..), None) => Ordering::Less,
+    | (None, Some(..)) => Ordering::Greater,
+    | (None, None) => Ordering::Equal,
+  }
+}
+
+/// Compares distances, putting unknown ones last.
+fn cmp_distance(a: Option<f64>, b: Option<f64>) -> Ordering {
+  match (a, b) {
+    | (Some(a), Some(b)) => a.total_cmp(&b),
+    | (Some(..), None) => Ordering::Less,
+    | (None, Some(..)) => Ordering::Greater,
+    | (None, None) => Ordering::Equal,
+  }
+}
+
+/// Renders the supported features, e.g. `DAITA`.
+fn features(features: &[Feature]) -> String {
+  if features.is_empty() {
+    return "-".to_string();
+  }
+
+  features
+    .iter()
+    .map(Feature::to_string)
+    .collect::<Vec<_>>()
+    .join(", ")
+}
+
+/// Renders the distinct address families used, e.g. `IPv4/IPv6`.
+fn families(families: &[AddressFamily]) -> String {
+  let mut families = families.to_vec();
+
+  families.sort();
+  families.dedup();
+
+  if families.is_empty() {
+    return "-".to_string();
+  }
+
+  families
+    .iter()
+    .map(AddressFamily::to_string)
+    .collect::<Vec<_>>()
+    .join("/")
+}
+
+/// Renders timings as a sparkline scaled to their own min and max. Longer runs are downsampled to
+/// [`SPARKLINE_WIDTH`] ticks, each showing the slowest timing of its bucket, so spikes stay
+/// visible.
+fn sparkline(timings: &[Duration]) -> String {
+  const TICKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+  let (Some(min), Some(max)) = (timings.iter().min(), timings.iter().max()) else {
+    return "-".to_string();
+  };
+
+  let range = (*max - *min).as_secs_f64();
+  let buckets = timings.len().min(SPARKLINE_WIDTH);
+
+  (0..buckets)
+    .map(|bucket| {
+      let start = bucket * timings.len() / buckets;
+      let end = (bucket + 1) * timings.len() / buckets;
+      let timing = timings[start..end].iter().max().unwrap_or(min);
+
+      if range > 0.0 {
+        let ratio = (*timing - *min).as_secs_f64() / range;
+        TICKS[(ratio * (TICKS.len() - 1) as f64).round() as usize]
+      } else {
+        TICKS[0]
+      }
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashSet;
+
+  use super::*;
+  use crate::relays::Protocol;
+
+  fn relay_with_hostname(hostname: &str) -> Relay {
+    Relay {
+      hostname: Some(hostname.to_string()),
+      ..Relay::from_target("127.0.0.1")
+    }
+  }
+
+  #[test]
+  fn strips_hostname_suffix() {
+    let reporter = Reporter::new(Vec::new(), ReporterConfig::new());
+    let relay = relay_with_hostname("se-sto-wg-001.relays.mullvad.net");
+
+    assert_eq!(reporter.hostname(&relay), Some("se-sto-wg-001"));
+  }
+
+  #[test]
+  fn keeps_hostname_without_suffix() {
+    let reporter = Reporter::new(Vec::new(), ReporterConfig::new().set_hostname_suffix(""));
+    let relay = relay_with_hostname("se-sto-wg-001.relays.mullvad.net");
+
+    assert_eq!(
+      reporter.hostname(&relay),
+      Some("se-sto-wg-001.relays.mullvad.net")
+    );
+    assert_eq!(reporter.hostname(&Relay::from_target("127.0.0.1")), None);
+  }
+
+  #[test]
+  fn renders_trimmed_hostname_and_keeps_full_one_in_json() {
+    let relay = relay_with_hostname("se-sto-wg-001.relays.mullvad.net");
+    let timed = RelayTimed::new(relay, vec![Duration::from_millis(5)]);
+    let reporter = Reporter::new(vec![timed], ReporterConfig::new().set_show_hostname(true));
+
+    let layout = reporter.layout(false);
+    let column = layout
+      .header
+      .iter()
+      .position(|name| name == "Hostname")
+      .unwrap();
+
+    assert_eq!(layout.records[0][column], "se-sto-wg-001");
+
+    let mut json = Vec::new();
+    reporter.write_json(&mut json).unwrap();
+    let json = serde_json::from_slice::<Value>(&json).unwrap();
+
+    assert_eq!(json[0]["hostname"], "se-sto-wg-001.relays.mullvad.net");
+  }
+
+  #[test]
+  fn renders_placeholder_for_missing_values() {
+    let timed = RelayTimed::new(Relay::from_target("127.0.0.1"), Vec::new());
+    let config = ReporterConfig::new()
+      .set_show_hostname(true)
+      .set_show_ema(true)
+      .set_show_sort_marker(false);
+    let reporter = Reporter::new(vec![timed], config);
+
+    let layout = reporter.layout(false);
+    let cell = |name: &str| {
+      let column = layout
+        .header
+        .iter()
+        .position(|header| header == name)
+        .unwrap();
+      layout.records[0][column].as_str()
+    };
+
+    for name in [
+      "Hostname",
+      "Protocol",
+      "Distance",
+      "RTT median",
+      "RTT mean",
+      "RTT EMA",
+    ] {
+      assert_eq!(cell(name), "-", "{name} column");
+    }
+  }
+
+  fn timed(ip: &str, timings: &[u64]) -> RelayTimed {
+    let timings = timings.iter().copied().map(Duration::from_millis).collect();
+
+    RelayTimed::new(Relay::from_target(ip), timings)
+  }
+
+  fn ranked_ips(reporter: &Reporter) -> Vec<&str> {
+    reporter
+      .ranked()
+      .map(|(_, timed)| timed.relay().ip.as_str())
+      .collect()
+  }
+
+  #[test]
+  fn keeps_input_order_for_equal_medians() {
+    let timings = vec![
+      timed("127.0.0.3", &[10, 20, 30]),
+      timed("127.0.0.1", &[10, 20, 30]),
+      timed("127.0.0.2", &[10, 20, 30]),
+    ];
+    let mut reporter = Reporter::new(timings, ReporterConfig::new());
+
+    reporter.sort();
+
+    assert_eq!(
+      ranked_ips(&reporter),
+      ["127.0.0.3", "127.0.0.1", "127.0.0.2"]
+    );
+  }
+
+  #[test]
+  fn breaks_median_ties_by_mean() {
+    let timings = vec![
+      timed("127.0.0.1", &[10, 20, 90]),
+      timed("127.0.0.2", &[10, 20, 30]),
+    ];
+    let mut reporter = Reporter::new(timings, ReporterConfig::new());
+
+    reporter.sort();
+
+    assert_eq!(ranked_ips(&reporter), ["127.0.0.2", "127.0.0.1"]);
+  }
+
+  #[test]
+  fn marks_sorted_column_only_when_enabled() {
+    let header = |show_sort_marker| {
+      let config = ReporterConfig::new()
+        .set_sort_by(SortBy::Distance)
+        .set_show_sort_marker(show_sort_marker);
+
+      Reporter::new(Vec::new(), config).layout(false).header
+    };
+
+    let marked = header(true);
+    assert!(marked.contains(&"Distance *".to_string()));
+    assert!(marked.contains(&"RTT median".to_string()));
+
+    let unmarked = header(false);
+    assert!(unmarked.contains(&"Distance".to_string()));
+    assert!(unmarked.iter().all(|name| !name.ends_with(" *")));
+  }
+
+  #[test]
+  fn renders_sparkline_per_timing() {
+    let timings = [10, 20, 80, 10].map(Duration::from_millis);
+
+    assert_eq!(sparkline(&timings), "▁▂█▁");
+    assert_eq!(sparkline(&[]), "-");
+  }
+
+  #[test]
+  fn downsamples_long_sparklines() {
+    let mut timings = vec![Duration::from_millis(10); 100];
+    timings[50] = Duration::from_millis(90);
+
+    let sparkline = sparkline(&timings);
+
+    assert_eq!(sparkline.chars().count(), SPARKLINE_WIDTH);
+    assert_eq!(sparkline.chars().filter(|tick| *tick == '█').count(), 1);
+  }
+
+  /// Checks the value against the subset of JSON Schema used by [`Reporter::json_schema`].
+  fn assert_matches_schema(schema: &Value, value: &Value, path: &str) {
+    if let Some(kind) = schema.get("type") {
+      let kinds = match kind {
+        | Value::Array(kinds) => kinds.iter().filter_map(Value::as_str).collect(),
+        | kind => vec![kind.as_str().unwrap()],
+      };
+
+      let matches = kinds.iter().any(|kind| {
+        match *kind {
+          | "array" => value.is_array(),
+          | "integer" => value.is_u64() || value.is_i64(),
+          | "null" => value.is_null(),
+          | "number" => value.is_number(),
+          | "object" => value.is_object(),
+          | "string" => value.is_string(),
+          | kind => panic!("unexpected type {kind} in the schema"),
+        }
+      });
+
+      assert!(matches, "{path} = {value} isn't of type {kind}");
+    }
+
+    if let Some(variants) = schema["enum"].as_array() {
+      assert!(
+        variants.contains(value),
+        "{path} = {value} isn't one of {variants:?}"
+      );
+    }
+
+    if let Some(items) = schema.get("items") {
+      for (idx, item) in value.as_array().unwrap().iter().enumerate() {
+        assert_matches_schema(items, item, &format!("{path}[{idx}]"));
+      }
+    }
+
+    if let Some(properties) = schema["properties"].as_object() {
+      let object = value.as_object().unwrap();
+
+      for key in object.keys() {
+        assert!(
+          properties.contains_key(key),
+          "{path}.{key} isn't in the schema"
+        );
+      }
+
+      for key in schema["required"].as_array().unwrap() {
+        let key = key.as_str().unwrap();
+        assert!(object.contains_key(key), "{path}.{key} is required");
+      }
+
+      for (key, value) in object {
+        assert_matches_schema(&properties[key], value, &format!("{path}.{key}"));
+      }
+    }
+  }
+
+  #[test]
+  fn writes_json_matching_schema() {
+    let relay = Relay {
+      hostname: Some("se-sto-wg-001".to_string()),
+      target: Some("relay.example".to_string()),
+      protocol: Some(Protocol::WireGuard),
+      country: "Sweden".to_string(),
+      city: "Stockholm".to_string(),
+      distance: Some(12.5),
+      ..Relay::from_target("127.0.0.1")
+    };
+
+    let timings = vec![
+      timed("127.0.0.2", &[]),
+      RelayTimed::new(relay, vec![Duration::from_millis(5)]),
+    ];
+
+    let mut json = Vec::new();
+    Reporter::new(timings, ReporterConfig::new())
+      .write_json(&mut json)
+      .unwrap();
+
+    let json = serde_json::from_slice::<Value>(&json).unwrap();
+
+    assert_matches_schema(&Reporter::json_schema(), &json, "$");
+  }
+
+  #[test]
+  fn archives_runs_within_the_same_millisecond_separately() {
+    let dir = std::env::temp_dir().join(format!("pingmole-archive-{}", std::process::id()));
+    let reporter = Reporter::new(vec![timed("127.0.0.1", &[5])], ReporterConfig::new());
+
+    let paths = (0..3)
+      .map(|_| reporter.archive(&dir, OutputFormat::Json, None).unwrap())
+      .collect::<HashSet<_>>();
+
+    assert_eq!(paths.len(), 3);
+
+    for path in &paths {
+      assert_eq!(path.extension().unwrap(), "json");
+
+      let json = serde_json::from_slice::<Value>(&fs::read(path).unwrap()).unwrap();
+      assert_eq!(json[0]["ip"], "127.0.0.1");
+    }
+
+    let geojson = reporter.archive(&dir, OutputFormat::GeoJson, None).unwrap();
+    assert_eq!(geojson.extension().unwrap(), "geojson");
+
+    assert!(matches!(
+      reporter.archive(&dir, OutputFormat::Table, None),
+      Err(ReporterError::ArchiveUnsupported)
+    ));
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn compares_numbers_by_magnitude() {
+    assert_eq!(cmp_natural("se-sto-wg-2", "se-sto-wg-10"), Ordering::Less);
+    assert_eq!(
+      cmp_natural("se-sto-wg-10", "se-sto-wg-2"),
+      Ordering::Greater
+    );
+    assert_eq!(
+      cmp_natural("se-sto-wg-010", "se-sto-wg-9"),
+      Ordering::Greater
+    );
+  }
+
+  #[test]
+  fn breaks_natural_order_ties() {
+    assert_eq!(cmp_natural("se-sto-wg-1", "se-sto-wg-1"), Ordering::Equal);
+    // Equal numbers with different leading zeros still get a stable order.
+    assert_eq!(cmp_natural("se-sto-wg-01", "se-sto-wg-1"), Ordering::Less);
+    assert_eq!(
+      cmp_natural("se-sto-wg-1", "se-sto-wg-01"),
+      Ordering::Greater
+    );
+  }
+
+  #[test]
+  fn ignores_case_in_natural_order() {
+    assert_eq!(cmp_natural("SE-sto-wg-2", "se-sto-wg-10"), Ordering::Less);
+    assert_eq!(cmp_natural("se-STO-wg-3", "se-sto-WG-2"), Ordering::Greater);
+    // Only a difference in case is still an order, not a tie.
+    assert_eq!(cmp_natural("SE-sto-wg-1", "se-sto-wg-1"), Ordering::Less);
+  }
+
+  #[test]
+  fn marks_hostname_column_when_sorting_by_it() {
+    let reporter = Reporter::new(
+      Vec::new(),
+      ReporterConfig::new()
+        .set_sort_by(SortBy::Hostname)
+        .set_show_hostname(true),
+    );
+
+    assert!(reporter
+      .layout(false)
+      .header
+      .contains(&"Hostname *".to_string()));
+  }
+}
@@ -0,0 +1,45 @@
+use std::path::PathBuf;
+
+use pingmole::{Coord, DistanceModel, RelaysLoader, RelaysLoaderConfig};
+
+fn fixture(name: &str) -> PathBuf {
+  PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+    .join("tests/fixtures")
+    .join(name)
+}
+
+fn config(include_inactive: bool) -> RelaysLoaderConfig {
+  RelaysLoaderConfig {
+    location: Coord::new(59.3289, 18.0649),
+    include_inactive,
+    distance_model: DistanceModel::GreatCircle,
+    relays_file: Some(fixture("relays.json")),
+    remote_fallback: false,
+    relays_url: None,
+  }
+}
+
+async fn hostnames(include_inactive: bool) -> Vec<String> {
+  let (relays, _) = RelaysLoader::new(config(include_inactive), Vec::new())
+    .load()
+    .await
+    .unwrap();
+
+  relays
+    .into_iter()
+    .filter_map(|relay| relay.hostname)
+    .collect()
+}
+
+#[tokio::test]
+async fn skips_inactive_relays_by_default() {
+  assert_eq!(hostnames(false).await, ["se-sto-wg-001", "se-sto-ovpn-001"]);
+}
+
+#[tokio::test]
+async fn keeps_inactive_relays_when_asked() {
+  assert_eq!(
+    hostnames(true).await,
+    ["se-sto-wg-001", "se-sto-wg-002", "se-sto-ovpn-001"]
+  );
+}